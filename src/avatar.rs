@@ -0,0 +1,196 @@
+// Identicons for relayed IRC users, so speakers can be told apart at a glance. They are plain
+// uncompressed PNGs, which is all Discord needs and keeps this free of an image library.
+
+const GRID: usize = 5;
+const CELL: usize = 20;
+const MARGIN: usize = 10;
+const SIZE: usize = GRID * CELL + 2 * MARGIN;
+const BACKGROUND: [u8; 3] = [240, 240, 240];
+
+// FNV-1a, which unlike the std hashers is guaranteed to stay the same between builds, so avatar
+// URLs (and the colours people get used to) are stable
+pub fn nick_hash(nick: &str) -> u64 {
+    nick.to_lowercase()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+fn hue_to_rgb(hue: u64) -> [u8; 3] {
+    // Fixed saturation and lightness, so every colour stands out against the background
+    let (chroma, lightness) = (0.5, 0.45);
+    let h = hue as f64 / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match hue / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+
+    [r, g, b].map(|c| ((c + m) * 255.0).round() as u8)
+}
+
+// The hash picks a colour and a 5x5 pattern mirrored left to right
+pub fn identicon(hash: u64) -> Vec<u8> {
+    let colour = hue_to_rgb((hash >> 32) % 360);
+    let filled = |row: usize, column: usize| {
+        let column = column.min(GRID - 1 - column);
+        hash >> (row * 3 + column) & 1 == 1
+    };
+
+    let mut pixels = Vec::with_capacity(SIZE * (1 + SIZE * 3));
+    for y in 0..SIZE {
+        // No filtering
+        pixels.push(0);
+        for x in 0..SIZE {
+            let inside =
+                (MARGIN..SIZE - MARGIN).contains(&x) && (MARGIN..SIZE - MARGIN).contains(&y);
+            let pixel = match inside && filled((y - MARGIN) / CELL, (x - MARGIN) / CELL) {
+                true => colour,
+                false => BACKGROUND,
+            };
+            pixels.extend_from_slice(&pixel);
+        }
+    }
+
+    png(SIZE as u32, SIZE as u32, &pixels)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xedb8_8320,
+            _ => crc >> 1,
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// An 8-bit RGB PNG, with the filtered scanlines stored in uncompressed deflate blocks
+fn png(width: u32, height: u32, scanlines: &[u8]) -> Vec<u8> {
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth, truecolour, and the only compression, filter and interlace methods there are
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    chunk(&mut png, b"IHDR", &header);
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = scanlines.chunks(u16::MAX as usize).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        let len = block.len() as u16;
+        zlib.push((i == blocks.len() - 1) as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(scanlines).to_be_bytes());
+    chunk(&mut png, b"IDAT", &zlib);
+
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    // The data of each chunk after the signature, checking lengths and CRCs on the way
+    fn chunks(png: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            chunks.push((kind, data));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    // Undoes the stored deflate blocks
+    fn inflate(zlib: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut rest = &zlib[2..];
+        loop {
+            let last = rest[0] == 1;
+            let len = u16::from_le_bytes([rest[1], rest[2]]);
+            assert_eq!(!len, u16::from_le_bytes([rest[3], rest[4]]));
+            data.extend_from_slice(&rest[5..5 + len as usize]);
+            rest = &rest[5 + len as usize..];
+            if last {
+                break;
+            }
+        }
+        assert_eq!(rest, adler32(&data).to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn nick_hash_is_fnv1a_and_case_insensitive() {
+        assert_eq!(nick_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(nick_hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(nick_hash("Alice"), nick_hash("alice"));
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn png_round_trips() {
+        // More than one stored block
+        let scanlines: Vec<u8> = (0..70_000).map(|i| i as u8).collect();
+        let png = png(1, 1, &scanlines);
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(inflate(chunks[1].1), scanlines);
+    }
+
+    #[test]
+    fn identicon_is_mirrored() {
+        let png = identicon(nick_hash("alice"));
+        let scanlines = inflate(chunks(&png)[1].1);
+        assert_eq!(scanlines.len(), SIZE * (1 + SIZE * 3));
+
+        for row in scanlines.chunks(1 + SIZE * 3) {
+            assert_eq!(row[0], 0);
+            let pixels: Vec<&[u8]> = row[1..].chunks(3).collect();
+            let mirrored: Vec<&[u8]> = pixels.iter().rev().copied().collect();
+            assert_eq!(pixels, mirrored);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use crate::config::{Config, ConfigFormat, IRCChannel, IRCServerConfig, Secret};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the config file
+    #[arg(
+        long,
+        global = true,
+        env = "BOUNCER_CONFIG",
+        default_value = "config.json"
+    )]
+    pub config: String,
+
+    /// Directory for persistent data (logs, databases, ...)
+    #[arg(long, global = true, env = "BOUNCER_DATA_DIR", default_value = ".")]
+    pub data_dir: PathBuf,
+
+    /// How much diagnostic output to print
+    #[arg(long, global = true, env = "BOUNCER_LOG_LEVEL", value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Format of the diagnostic output
+    #[arg(long, global = true, env = "BOUNCER_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Include message contents in logs instead of redacting them
+    #[arg(long, global = true)]
+    pub log_message_bodies: bool,
+
+    /// Validate the config file and exit (same as the validate subcommand)
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the bouncer (the default)
+    Run,
+    /// Validate the config file and exit
+    Validate,
+    /// Generate a new config file based on the sample config
+    GenConfig {
+        /// Where to write the config, defaults to stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Format to write, defaults to the output file's extension (or JSON)
+        #[arg(short, long, value_enum)]
+        format: Option<ConfigFormat>,
+        /// Prompt for each value instead of writing placeholders
+        #[arg(short, long)]
+        interactive: bool,
+        /// Overwrite the output file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// Returns the process exit code
+pub fn validate(config_path: &str) -> i32 {
+    match Config::load(config_path) {
+        Ok(_) => {
+            println!("{} is valid", config_path);
+            0
+        }
+        Err(why) => {
+            eprintln!("{}", why);
+            1
+        }
+    }
+}
+
+fn prompt(question: &str, default: &str) -> String {
+    eprint!("{} [{}]: ", question, default);
+    io::stderr().flush().unwrap();
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).unwrap();
+
+    match answer.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    }
+}
+
+// Mirrors config.json.sample
+fn template(interactive: bool) -> Config {
+    let ask = |question: &str, default: &str| match interactive {
+        true => prompt(question, default),
+        false => default.to_string(),
+    };
+
+    let token = ask("Discord bot token", "<discord-bot-token>");
+    let discord_user_id = ask("Your Discord account id", "0").parse().unwrap_or(0);
+    let address = ask("IRC server address", "irc.libera.chat:6697");
+    let tls = ask("Use TLS (y/n)", "y").starts_with(['y', 'Y']);
+    let nick = ask("IRC nick", "irc_nick_here");
+    let password = ask("SASL password (blank for none)", "");
+    let general_webhook = ask(
+        "Webhook for server messages",
+        "https://discord.com/api/webhooks/id/token",
+    );
+
+    let mut channels = Vec::new();
+
+    if interactive {
+        loop {
+            let name = prompt("IRC channel to bridge (blank to finish)", "");
+            if name.is_empty() {
+                break;
+            }
+
+            channels.push(IRCChannel {
+                discord_channel: prompt("Discord channel id", "0").parse().unwrap_or(0),
+                webhook_url: Secret::new(prompt(
+                    "Webhook for the channel",
+                    "https://discord.com/api/webhooks/id/token",
+                )),
+                name,
+                access: Vec::new(),
+            });
+        }
+    } else {
+        channels.push(IRCChannel {
+            name: "##john-test".to_string(),
+            discord_channel: 1,
+            webhook_url: Secret::new("https://discord.com/api/webhooks/id/token".to_string()),
+            access: Vec::new(),
+        });
+    }
+
+    Config {
+        token: Secret::new(token),
+        discord_user_id,
+        servers: vec![IRCServerConfig {
+            address,
+            tls,
+            nick,
+            password: match password.is_empty() {
+                true => None,
+                false => Some(Secret::new(password)),
+            },
+            general_webhook: Secret::new(general_webhook),
+            channels,
+            log_format: None,
+            edit_style: None,
+            access: Vec::new(),
+            reaction_fallback: None,
+            topic_to_irc: None,
+        }],
+        quit_message: None,
+        shutdown_timeout_secs: None,
+        message_retention_days: None,
+        http_address: None,
+        late_delivery_threshold_secs: None,
+        away: None,
+        avatars: None,
+        username_format: None,
+        puppets: None,
+        access: Vec::new(),
+    }
+}
+
+// Returns the process exit code
+pub fn gen_config(
+    output: Option<String>,
+    format: Option<ConfigFormat>,
+    interactive: bool,
+    force: bool,
+) -> i32 {
+    if let Some(path) = &output {
+        if !force && fs::metadata(path).is_ok() {
+            eprintln!("{} already exists, pass --force to overwrite it", path);
+            return 1;
+        }
+    }
+
+    let config = template(interactive);
+    let format = format.unwrap_or_else(|| match &output {
+        Some(path) => ConfigFormat::from_path(path),
+        None => ConfigFormat::Json,
+    });
+
+    let serialized = match format.serialize(&config) {
+        Ok(serialized) => serialized,
+        Err(why) => {
+            eprintln!("Unable to generate config: {}", why);
+            return 1;
+        }
+    };
+
+    match output {
+        Some(path) => match fs::write(&path, serialized) {
+            Ok(()) => {
+                eprintln!("Wrote {}, fill in the placeholders and run validate", path);
+                0
+            }
+            Err(why) => {
+                eprintln!("Unable to write {}: {}", path, why);
+                1
+            }
+        },
+        None => {
+            println!("{}", serialized);
+            0
+        }
+    }
+}
@@ -0,0 +1,842 @@
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Table};
+
+use crate::textlog::TextLogFormat;
+
+// Most networks allow far less, this only keeps commands with the name well within a line
+const MAX_CHANNEL_NAME_LENGTH: usize = 200;
+
+// How a secret is written in the config: either inline, or a reference resolved at load time
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    Plain(String),
+    Env { env: String },
+    File { file: String },
+}
+
+// A config value that must never end up in logs. The source is kept so saving
+// the config writes back the reference rather than the resolved value.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "SecretSource", into = "SecretSource")]
+pub struct Secret {
+    source: SecretSource,
+    value: String,
+}
+
+impl From<SecretSource> for Secret {
+    fn from(source: SecretSource) -> Self {
+        let value = match &source {
+            SecretSource::Plain(value) => String::from(value),
+            _ => String::new(),
+        };
+        Secret { source, value }
+    }
+}
+
+impl From<Secret> for SecretSource {
+    fn from(secret: Secret) -> Self {
+        secret.source
+    }
+}
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret::from(SecretSource::Plain(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    fn resolve(&mut self, field: &str, problems: &mut Vec<String>) {
+        match &self.source {
+            SecretSource::Plain(_) => {}
+            SecretSource::Env { env } => match env::var(env) {
+                Ok(value) => self.value = value,
+                Err(_) => problems.push(format!(
+                    "{}: environment variable {} is not set",
+                    field, env
+                )),
+            },
+            SecretSource::File { file } => match fs::read_to_string(file) {
+                // Secret files usually end with a newline that isn't part of the secret
+                Ok(value) => self.value = value.trim_end_matches(['\r', '\n']).to_string(),
+                Err(e) => problems.push(format!("{}: unable to read {}: {}", field, file, e)),
+            },
+        }
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "Secret(<redacted>)")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "<redacted>")
+    }
+}
+
+// How Discord edits are shown on networks that can't redact the original message
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditStyle {
+    // * correction: <the edited message>
+    Correction,
+    // s/old/new/
+    Sed,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IRCChannel {
+    pub name: String,
+    pub discord_channel: u64,
+    pub webhook_url: Secret,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub access: Vec<AccessRule>,
+}
+
+// What Discord users other than the owner may do, each level allowing everything below it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLevel {
+    // Searching and exporting logs
+    ReadOnly,
+    // Sending messages to IRC
+    Talk,
+    // Managing bridges and sending raw lines with /raw
+    Admin,
+}
+
+// Grants a level to a Discord user or to everyone with a role
+#[derive(Serialize, Deserialize)]
+pub struct AccessRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<u64>,
+    pub level: AccessLevel,
+}
+
+impl AccessRule {
+    fn validate(&self, field: &str, problems: &mut Vec<String>) {
+        if self.user.is_some() == self.role.is_some() {
+            problems.push(format!("{}: must have either a user or a role", field));
+        }
+    }
+}
+
+// The highest level the rules grant, None when none of them apply
+fn access_level(rules: &[AccessRule], user: u64, roles: &[u64]) -> Option<AccessLevel> {
+    rules
+        .iter()
+        .filter(|rule| {
+            rule.user == Some(user) || rule.role.is_some_and(|role| roles.contains(&role))
+        })
+        .map(|rule| rule.level)
+        .max()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IRCServerConfig {
+    pub address: String,
+    pub tls: bool,
+    pub nick: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    pub general_webhook: Secret,
+    pub channels: Vec<IRCChannel>,
+    // Line format of the plain-text logs in <data_dir>/logs, nothing is written when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<TextLogFormat>,
+    // Defaults to correction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edit_style: Option<EditStyle>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub access: Vec<AccessRule>,
+    // Sent as an action where reactions can't be tagged, with {emoji} and {nick} filled in.
+    // Defaults to "reacted {emoji} to {nick}'s message", nothing is sent when empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reaction_fallback: Option<String>,
+    // Discord topic edits by the owner are set on IRC too, off by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic_to_irc: Option<bool>,
+}
+
+// Away messages set on every network while the owner is idle, on do not disturb or offline in Discord
+#[derive(Serialize, Deserialize)]
+pub struct AwayConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dnd_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_message: Option<String>,
+    // How long a presence has to last before it is mirrored, defaults to 30
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grace_secs: Option<u64>,
+}
+
+// Avatars of relayed IRC users. Overrides by account win over ones by nick, then come identicons,
+// then the default.
+#[derive(Serialize, Deserialize)]
+pub struct AvatarConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_url: Option<String>,
+    // Where Discord can reach the HTTP server, identicons are used when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identicon_base_url: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub nicks: HashMap<String, String>,
+    // By services account, on networks with account-tag
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub accounts: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PuppetUser {
+    pub discord_user_id: u64,
+    // Overrides the nick_format for this user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nick: Option<String>,
+    // For SASL PLAIN, with the nick as the account name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+}
+
+// Separate IRC connections for Discord users other than the owner, so they talk as themselves
+#[derive(Serialize, Deserialize)]
+pub struct PuppetConfig {
+    // Nick of each puppet, with {name} filled in from the Discord username. Defaults to {name}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nick_format: Option<String>,
+    // Puppets quit after this long without talking. Defaults to an hour
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    // Only these users get puppets when set. Everyone else allowed to talk is relayed by the bridge's
+    // own connection, as <name> message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<PuppetUser>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    pub token: Secret,
+    pub discord_user_id: u64,
+    pub servers: Vec<IRCServerConfig>,
+    // Sent to every network when shutting down
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quit_message: Option<String>,
+    // How long to wait for networks to disconnect and queued messages to be delivered on shutdown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_timeout_secs: Option<u64>,
+    // Relayed messages older than this are deleted from the message log, kept forever when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_retention_days: Option<u64>,
+    // Address to serve the HTTP /metrics, /healthz and /readyz endpoints on, disabled when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_address: Option<String>,
+    // Messages reaching Discord later than this after being sent on IRC show their original time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub late_delivery_threshold_secs: Option<u64>,
+    // Mirrors the owner's Discord presence as AWAY, which needs the privileged presence intent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub away: Option<AwayConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatars: Option<AvatarConfig>,
+    // Webhook username of relayed IRC users, with {nick}, {prefix} (their channel status, e.g. @)
+    // and {network} filled in. Defaults to {nick}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub puppets: Option<PuppetConfig>,
+    // Applies to every network, unless a network or channel has rules for the same user
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub access: Vec<AccessRule>,
+}
+
+fn validate_webhook_url(url: &Secret, field: &str, problems: &mut Vec<String>) {
+    lazy_static! {
+        static ref WEBHOOK_RE: Regex = Regex::new(
+            r"^https://(?:ptb\.|canary\.)?discord(?:app)?\.com/api/(?:v\d+/)?webhooks/\d+/[\w-]+$"
+        )
+        .unwrap();
+    }
+
+    // The URL itself contains the webhook token, so it isn't echoed back
+    if !WEBHOOK_RE.is_match(url.expose()) {
+        problems.push(format!(
+            "{}: not a Discord webhook URL (expected https://discord.com/api/webhooks/<id>/<token>)",
+            field
+        ));
+    }
+}
+
+// Also keeps names that would break the line they are sent in, or not fit on it
+pub fn is_channel_name(name: &str) -> bool {
+    name.starts_with(['#', '&'])
+        && name.len() <= MAX_CHANNEL_NAME_LENGTH
+        && !name.contains([' ', ',', '\x07', '\0', '\r', '\n'])
+}
+
+// Brings a TOML table up to date with another, leaving whatever didn't change as it was written
+fn merge_toml(old: &mut Table, new: &Table) {
+    old.retain(|key, _| new.contains_key(key));
+
+    for (key, new_item) in new.iter() {
+        match old.get_mut(key) {
+            Some(old_item) => merge_toml_item(old_item, new_item),
+            None => {
+                old.insert(key, new_item.clone());
+            }
+        }
+    }
+}
+
+fn merge_toml_item(old: &mut Item, new: &Item) {
+    match (old, new) {
+        (Item::Table(old), Item::Table(new)) => merge_toml(old, new),
+        // Matched up by position, so removing an entry moves the comments of the ones after it
+        (Item::ArrayOfTables(old), Item::ArrayOfTables(new)) => {
+            while old.len() > new.len() {
+                old.remove(old.len() - 1);
+            }
+            for (i, new) in new.iter().enumerate() {
+                match old.get_mut(i) {
+                    Some(old) => merge_toml(old, new),
+                    None => old.push(new.clone()),
+                }
+            }
+        }
+        (Item::Value(old), Item::Value(new)) => {
+            let mut undecorated = old.clone();
+            undecorated.decor_mut().clear();
+
+            // A changed value keeps the comment after it
+            if undecorated.to_string() != new.to_string() {
+                let decor = old.decor().clone();
+                *old = new.clone();
+                *old.decor_mut() = decor;
+            }
+        }
+        (old, new) => *old = new.clone(),
+    }
+}
+
+fn validate_address(address: &str, field: &str, problems: &mut Vec<String>) {
+    let valid = match address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p > 0),
+        None => false,
+    };
+
+    if !valid {
+        problems.push(format!(
+            "{}: '{}' must be in the form host:port (e.g. irc.libera.chat:6697)",
+            field, address
+        ));
+    }
+}
+
+impl IRCServerConfig {
+    fn validate(&self, field: &str, problems: &mut Vec<String>) {
+        validate_address(&self.address, &format!("{}.address", field), problems);
+        validate_webhook_url(
+            &self.general_webhook,
+            &format!("{}.general_webhook", field),
+            problems,
+        );
+
+        if self.nick.is_empty() || self.nick.contains(char::is_whitespace) {
+            problems.push(format!(
+                "{}.nick: '{}' is not a valid IRC nick",
+                field, self.nick
+            ));
+        }
+
+        // IRC channel names are case-insensitive
+        let mut names = HashSet::new();
+
+        for (i, channel) in self.channels.iter().enumerate() {
+            let channel_field = format!("{}.channels[{}]", field, i);
+
+            if !is_channel_name(&channel.name) {
+                problems.push(format!(
+                    "{}.name: '{}' is not a valid IRC channel name",
+                    channel_field, channel.name
+                ));
+            }
+
+            if !names.insert(channel.name.to_lowercase()) {
+                problems.push(format!(
+                    "{}.name: {} is listed more than once for {}",
+                    channel_field, channel.name, self.address
+                ));
+            }
+
+            validate_webhook_url(
+                &channel.webhook_url,
+                &format!("{}.webhook_url", channel_field),
+                problems,
+            );
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    // Anything that isn't recognisably TOML or YAML is treated as JSON, the original format
+    pub fn from_path(path: &str) -> ConfigFormat {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    // Parse errors are reported as path:line:column so editors can jump straight to them
+    fn parse(&self, contents: &str, path: &str) -> Result<Config, String> {
+        match self {
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| {
+                format!(
+                    "{}:{}:{}: {}",
+                    path,
+                    e.line(),
+                    e.column(),
+                    // serde_json appends the position to its message, which we already report
+                    e.to_string().split(" at line ").next().unwrap_or_default()
+                )
+            }),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| {
+                let offset = e.span().map_or(0, |span| span.start);
+                let before = &contents[..offset];
+                let line = before.matches('\n').count() + 1;
+                let column = offset - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                format!("{}:{}:{}: {}", path, line, column, e.message())
+            }),
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| match e.location() {
+                Some(location) => format!(
+                    "{}:{}:{}: {}",
+                    path,
+                    location.line(),
+                    location.column(),
+                    // serde_yaml appends the position to its message, which we already report
+                    e.to_string().split(" at line ").next().unwrap_or_default()
+                ),
+                None => format!("{}: {}", path, e),
+            }),
+        }
+    }
+
+    pub fn serialize(
+        &self,
+        config: &Config,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+            ConfigFormat::Toml => toml::to_string_pretty(config)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(config)?,
+        })
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+
+        let mut config = ConfigFormat::from_path(path).parse(&contents, path)?;
+
+        // Unresolved secrets are empty, so validating them would only add noise
+        let problems = match config.resolve_secrets() {
+            problems if !problems.is_empty() => problems,
+            _ => config.validate().err().unwrap_or_default(),
+        };
+
+        if !problems.is_empty() {
+            bail!(format!(
+                "{} is invalid:\n  - {}",
+                path,
+                problems.join("\n  - ")
+            ));
+        }
+
+        Ok(config)
+    }
+
+    fn resolve_secrets(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        self.token.resolve("token", &mut problems);
+
+        for (i, server) in self.servers.iter_mut().enumerate() {
+            let field = format!("servers[{}]", i);
+
+            if let Some(password) = &mut server.password {
+                password.resolve(&format!("{}.password", field), &mut problems);
+            }
+
+            server
+                .general_webhook
+                .resolve(&format!("{}.general_webhook", field), &mut problems);
+
+            for (j, channel) in server.channels.iter_mut().enumerate() {
+                channel.webhook_url.resolve(
+                    &format!("{}.channels[{}].webhook_url", field, j),
+                    &mut problems,
+                );
+            }
+        }
+
+        if let Some(puppets) = &mut self.puppets {
+            for (i, user) in puppets.users.iter_mut().enumerate() {
+                if let Some(password) = &mut user.password {
+                    password.resolve(&format!("puppets.users[{}].password", i), &mut problems);
+                }
+            }
+        }
+
+        problems
+    }
+
+    // Collects every problem rather than stopping at the first, so they can all be fixed in one go
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        if self.token.expose().is_empty() {
+            problems.push("token: must not be empty".to_string());
+        }
+
+        if let Some(http_address) = &self.http_address {
+            if http_address.parse::<SocketAddr>().is_err() {
+                problems.push(format!(
+                    "http_address: '{}' must be an IP address and port (e.g. 127.0.0.1:9090)",
+                    http_address
+                ));
+            }
+        }
+
+        for (i, rule) in self.access.iter().enumerate() {
+            rule.validate(&format!("access[{}]", i), &mut problems);
+        }
+
+        let mut addresses = HashSet::new();
+        let mut discord_channels = HashSet::new();
+
+        for (i, server) in self.servers.iter().enumerate() {
+            let field = format!("servers[{}]", i);
+
+            if !addresses.insert(&server.address) {
+                problems.push(format!(
+                    "{}.address: {} is configured more than once",
+                    field, server.address
+                ));
+            }
+
+            server.validate(&field, &mut problems);
+
+            for (j, rule) in server.access.iter().enumerate() {
+                rule.validate(&format!("{}.access[{}]", field, j), &mut problems);
+            }
+
+            for (j, channel) in server.channels.iter().enumerate() {
+                if !discord_channels.insert(channel.discord_channel) {
+                    problems.push(format!(
+                        "{}.channels[{}].discord_channel: {} is already mapped to another IRC channel",
+                        field, j, channel.discord_channel
+                    ));
+                }
+
+                for (k, rule) in channel.access.iter().enumerate() {
+                    rule.validate(
+                        &format!("{}.channels[{}].access[{}]", field, j, k),
+                        &mut problems,
+                    );
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // TOML configs are edited in place, keeping their comments and formatting. JSON and YAML ones
+    // are written out anew
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let format = ConfigFormat::from_path(path);
+        let mut contents = format.serialize(self)?;
+
+        if let (ConfigFormat::Toml, Ok(existing)) = (format, fs::read_to_string(path)) {
+            let mut document: DocumentMut = existing.parse()?;
+            merge_toml(
+                document.as_table_mut(),
+                contents.parse::<DocumentMut>()?.as_table(),
+            );
+            contents = document.to_string();
+        }
+
+        // Write to a temporary file first and rename it over the original,
+        // so a crash mid-write can never leave behind a truncated config
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn server_mut(&mut self, address: &str) -> Option<&mut IRCServerConfig> {
+        self.servers.iter_mut().find(|s| s.address == address)
+    }
+
+    // The owner can do anything. Everyone else gets the level from the rules of the channel,
+    // falling back to those of its network, then the global ones
+    pub fn access_level(
+        &self,
+        user: u64,
+        roles: &[u64],
+        network: Option<&str>,
+        channel: Option<&str>,
+    ) -> Option<AccessLevel> {
+        if user == self.discord_user_id {
+            return Some(AccessLevel::Admin);
+        }
+
+        let server = network.and_then(|addr| self.servers.iter().find(|s| s.address == addr));
+        let channel = server.zip(channel).and_then(|(server, name)| {
+            server
+                .channels
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name))
+        });
+
+        channel
+            .and_then(|channel| access_level(&channel.access, user, roles))
+            .or_else(|| server.and_then(|server| access_level(&server.access, user, roles)))
+            .or_else(|| access_level(&self.access, user, roles))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(old: &str, new: &str) -> String {
+        let mut document: DocumentMut = old.parse().unwrap();
+        merge_toml(
+            document.as_table_mut(),
+            new.parse::<DocumentMut>().unwrap().as_table(),
+        );
+        document.to_string()
+    }
+
+    #[test]
+    fn merge_toml_keeps_comments() {
+        let old = "# The bot\ntoken = \"abc\" # from the portal\n\n[[servers]]\n# Libera\naddress = \"irc.libera.chat:6697\"\n";
+        let new = "token = \"abc\"\n\n[[servers]]\naddress = \"irc.libera.chat:6697\"\n";
+        assert_eq!(merged(old, new), old);
+    }
+
+    #[test]
+    fn merge_toml_updates_values() {
+        let old = "token = \"abc\" # from the portal\nquit_message = \"bye\"\n";
+        let new = "token = \"def\"\n";
+        assert_eq!(merged(old, new), "token = \"def\" # from the portal\n");
+    }
+
+    #[test]
+    fn merge_toml_adds_and_removes_tables() {
+        let old = "[[servers]]\n# Libera\naddress = \"a:1\"\n\n[[servers.channels]]\nname = \"#one\"\n\n[[servers.channels]]\nname = \"#two\"\n";
+        let new = "[[servers]]\naddress = \"a:1\"\n\n[[servers.channels]]\nname = \"#one\"\n\n[[servers.channels]]\nname = \"#three\"\n\n[[servers.channels]]\nname = \"#four\"\n";
+        let result = merged(old, new);
+
+        assert!(result.contains("# Libera"));
+        assert!(!result.contains("#two"));
+        let reparsed: toml::Value = toml::from_str(&result).unwrap();
+        let names: Vec<&str> = reparsed["servers"][0]["channels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["#one", "#three", "#four"]);
+    }
+
+    const ACCESS: &str = r##"
+token = "abc"
+discord_user_id = 1
+access = [
+    { role = 10, level = "read_only" },
+    { user = 2, level = "talk" },
+]
+
+[[servers]]
+address = "a:1"
+tls = true
+nick = "bouncer"
+general_webhook = "https://discord.com/api/webhooks/1/a"
+access = [
+    { user = 2, level = "read_only" },
+    { role = 11, level = "talk" },
+]
+
+[[servers.channels]]
+name = "#one"
+discord_channel = 100
+webhook_url = "https://discord.com/api/webhooks/2/b"
+access = [{ user = 3, level = "admin" }]
+
+[[servers.channels]]
+name = "#two"
+discord_channel = 200
+webhook_url = "https://discord.com/api/webhooks/3/c"
+"##;
+
+    #[test]
+    fn access_level_owner_is_admin() {
+        let config: Config = toml::from_str(ACCESS).unwrap();
+        assert_eq!(
+            config.access_level(1, &[], None, None),
+            Some(AccessLevel::Admin)
+        );
+        assert_eq!(
+            config.access_level(1, &[], Some("a:1"), Some("#two")),
+            Some(AccessLevel::Admin)
+        );
+    }
+
+    #[test]
+    fn access_level_narrower_rules_win() {
+        let config: Config = toml::from_str(ACCESS).unwrap();
+        assert_eq!(
+            config.access_level(2, &[], None, None),
+            Some(AccessLevel::Talk)
+        );
+        assert_eq!(
+            config.access_level(2, &[], Some("other:1"), None),
+            Some(AccessLevel::Talk)
+        );
+        assert_eq!(
+            config.access_level(2, &[], Some("a:1"), None),
+            Some(AccessLevel::ReadOnly)
+        );
+        assert_eq!(
+            config.access_level(2, &[], Some("a:1"), Some("#one")),
+            Some(AccessLevel::ReadOnly)
+        );
+        assert_eq!(
+            config.access_level(3, &[], Some("a:1"), Some("#ONE")),
+            Some(AccessLevel::Admin)
+        );
+        assert_eq!(config.access_level(3, &[], Some("a:1"), Some("#two")), None);
+    }
+
+    #[test]
+    fn access_level_takes_highest_matching_rule() {
+        let config: Config = toml::from_str(ACCESS).unwrap();
+        assert_eq!(
+            config.access_level(4, &[10, 11], Some("a:1"), None),
+            Some(AccessLevel::Talk)
+        );
+        assert_eq!(
+            config.access_level(4, &[10, 11], None, None),
+            Some(AccessLevel::ReadOnly)
+        );
+        assert_eq!(config.access_level(5, &[], Some("a:1"), Some("#one")), None);
+    }
+
+    const VALID: &str = r##"
+token = "abc"
+discord_user_id = 1
+
+[[servers]]
+address = "irc.example.org:6697"
+tls = true
+nick = "bouncer"
+general_webhook = "https://discord.com/api/webhooks/1/a"
+
+[[servers.channels]]
+name = "#one"
+discord_channel = 100
+webhook_url = "https://discord.com/api/webhooks/2/b"
+"##;
+
+    #[test]
+    fn validate() {
+        let second = |name: &str, discord_channel: u64| {
+            format!(
+                "{}\n[[servers.channels]]\nname = \"{}\"\ndiscord_channel = {}\nwebhook_url = \"https://discord.com/api/webhooks/3/c\"\n",
+                VALID, name, discord_channel
+            )
+        };
+
+        let cases = [
+            (String::from(VALID), None),
+            (second("#two", 200), None),
+            (
+                second("#ONE", 200),
+                Some("servers[0].channels[1].name: #ONE is listed more than once"),
+            ),
+            (
+                second("#two", 100),
+                Some("servers[0].channels[1].discord_channel: 100 is already mapped"),
+            ),
+            (
+                VALID.replace("webhooks/2/b", "webhooks/2"),
+                Some("servers[0].channels[0].webhook_url: not a Discord webhook URL"),
+            ),
+            (
+                VALID.replace("https://discord.com/api/webhooks/1/a", ""),
+                Some("servers[0].general_webhook: not a Discord webhook URL"),
+            ),
+        ];
+
+        for (contents, expected) in cases {
+            let config: Config = toml::from_str(&contents).unwrap();
+            let problems = config.validate().err().unwrap_or_default();
+
+            match expected {
+                None => assert!(problems.is_empty(), "{:?}", problems),
+                Some(expected) => assert!(
+                    problems.len() == 1 && problems[0].starts_with(expected),
+                    "expected {}, got {:?}",
+                    expected,
+                    problems
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn general_webhook_is_required() {
+        let contents = VALID.replace(
+            "general_webhook = \"https://discord.com/api/webhooks/1/a\"\n",
+            "",
+        );
+        assert!(toml::from_str::<Config>(&contents).is_err());
+    }
+}
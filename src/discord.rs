@@ -1,146 +1,1674 @@
 use serenity::{
     async_trait,
     model::{
-        channel::Message,
-        id::{ChannelId, GuildId, UserId},
+        channel::{Attachment, Channel, ChannelType, Message, Reaction, ReactionType},
+        event::{MessageUpdateEvent, PresenceUpdateEvent, TypingStartEvent},
+        id::{ChannelId, GuildId, MessageId, UserId},
+        user::{OnlineStatus, User},
     },
+    prelude::TypeMapKey,
+    Error as SerenityError,
 };
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 
+use crate::avatar;
+use crate::config::{
+    is_channel_name, AccessLevel, AvatarConfig, Config, IRCChannel, IRCServerConfig, Secret,
+};
+use crate::irc;
+use crate::logging;
 use crate::message;
+use crate::metrics::METRICS;
+use crate::storage::{LoggedMessage, RelayedMessage, SearchQuery, Storage};
+use crate::textlog;
+use serenity::client::bridge::gateway::GatewayIntents;
 use serenity::client::{Client, Context, EventHandler};
 use serenity::framework::standard::{
-    macros::{command, group},
-    CommandResult, StandardFramework,
+    macros::{command, group, hook},
+    Args, CommandResult, StandardFramework,
 };
 
-use serenity::utils::Parse;
-use tokio::sync::broadcast::Sender;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio::task::{self, JoinHandle};
+
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{debug, error, field, info_span, warn, Instrument, Span};
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct IRCServer {
+    addr: String,
+    channel: String,
+}
+
+struct DiscordChannel {
+    webhook_id: u64,
+    webhook_token: String,
+}
+
+#[derive(Default)]
+pub struct BridgeMaps {
+    discord_irc_map: HashMap<ChannelId, IRCServer>,
+    irc_discord_map: HashMap<IRCServer, DiscordChannel>,
+}
+
+impl BridgeMaps {
+    pub fn from_config(servers: &[IRCServerConfig]) -> Result<BridgeMaps, String> {
+        let mut maps = BridgeMaps::default();
+
+        for server in servers {
+            for channel in &server.channels {
+                maps.insert_channel(&server.address, channel)?;
+            }
+
+            // Add general channel for server-messages, DMs, etc.
+            maps.irc_discord_map.insert(
+                IRCServer {
+                    addr: String::from(&server.address),
+                    channel: "".to_string(),
+                },
+                webhook_from_url(server.general_webhook.expose())
+                    .ok_or(format!("Invalid general_webhook for {}", server.address))?,
+            );
+        }
+
+        Ok(maps)
+    }
+
+    fn insert_channel(&mut self, addr: &str, channel: &IRCChannel) -> Result<(), String> {
+        self.irc_discord_map.insert(
+            IRCServer {
+                addr: String::from(addr),
+                channel: String::from(&channel.name),
+            },
+            webhook_from_url(channel.webhook_url.expose()).ok_or(format!(
+                "Invalid webhook_url for {} on {}",
+                channel.name, addr
+            ))?,
+        );
+
+        self.discord_irc_map.insert(
+            ChannelId(channel.discord_channel),
+            IRCServer {
+                addr: String::from(addr),
+                channel: String::from(&channel.name),
+            },
+        );
+        Ok(())
+    }
+
+    fn remove_channel(&mut self, addr: &str, name: &str) {
+        self.irc_discord_map.remove(&IRCServer {
+            addr: String::from(addr),
+            channel: String::from(name),
+        });
+        self.discord_irc_map
+            .retain(|_, irc| !(irc.addr == addr && irc.channel == name));
+    }
+
+    fn discord_channel(&self, addr: &str, name: &str) -> Option<ChannelId> {
+        self.discord_irc_map
+            .iter()
+            .find(|(_, irc)| irc.addr == addr && irc.channel == name)
+            .map(|(id, _)| *id)
+    }
+}
+
+// Shared between the event handler, the bridge-management commands and config reloads
+pub struct BridgeState {
+    pub irc_tx: Sender<message::BouncerMessage>,
+    pub maps: RwLock<BridgeMaps>,
+    pub config: Mutex<Config>,
+    pub config_path: String,
+    pub connections: Mutex<HashMap<String, JoinHandle<()>>>,
+    pub drained: Notify,
+    pub dead_letters: Mutex<VecDeque<message::BouncerMessage>>,
+    // Set once the Discord cache is ready and the delivery loop is running
+    pub discord_ready: AtomicBool,
+    pub storage: Arc<Storage>,
+    // Where the plain-text logs of each network are written
+    pub logs_dir: PathBuf,
+    // The away message set on every network, None while the owner is around
+    pub away: Mutex<Option<String>>,
+    // A presence change waiting out the grace period, with the away message it will set
+    pending_away: Mutex<Option<(Option<String>, JoinHandle<()>)>>,
+    // Connections speaking for other Discord users, by network and user
+    puppets: Mutex<HashMap<(String, UserId), Puppet>>,
+    puppet_nicks: irc::PuppetNicks,
+    // When each user was last told they can't talk, so chatting in a bridged channel isn't answered every time
+    talk_denials: Mutex<HashMap<UserId, Instant>>,
+}
+
+struct Puppet {
+    handle: JoinHandle<()>,
+    nick: String,
+    last_active: Instant,
+}
+
+impl BridgeState {
+    pub fn new(
+        config: Config,
+        config_path: String,
+        irc_tx: Sender<message::BouncerMessage>,
+        storage: Storage,
+        logs_dir: PathBuf,
+    ) -> Result<BridgeState, String> {
+        Ok(BridgeState {
+            irc_tx,
+            maps: RwLock::new(BridgeMaps::from_config(&config.servers)?),
+            config: Mutex::new(config),
+            config_path,
+            connections: Mutex::new(HashMap::new()),
+            drained: Notify::new(),
+            dead_letters: Mutex::new(VecDeque::new()),
+            discord_ready: AtomicBool::new(false),
+            storage: Arc::new(storage),
+            logs_dir,
+            away: Mutex::new(None),
+            pending_away: Mutex::new(None),
+            puppets: Mutex::new(HashMap::new()),
+            puppet_nicks: irc::PuppetNicks::default(),
+            talk_denials: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn connect(&self, server: &IRCServerConfig) {
+        let handle = irc::spawn_connection(
+            server,
+            self.irc_tx.clone(),
+            &self.logs_dir,
+            Arc::clone(&self.storage),
+            self.away.lock().await.clone(),
+            self.puppet_nicks.clone(),
+        );
+        self.connections
+            .lock()
+            .await
+            .insert(String::from(&server.address), handle);
+    }
+
+    // Presences are only mirrored once they have lasted the grace period, so brief changes don't flap
+    pub async fn presence_changed(self: &Arc<Self>, status: OnlineStatus) {
+        let (away, grace) = {
+            let config = self.config.lock().await;
+            let messages = match &config.away {
+                Some(messages) => messages,
+                None => return,
+            };
+
+            let away = match status {
+                OnlineStatus::Online => None,
+                OnlineStatus::Idle => Some(messages.idle_message.as_deref().unwrap_or("Idle")),
+                OnlineStatus::DoNotDisturb => {
+                    Some(messages.dnd_message.as_deref().unwrap_or("Do not disturb"))
+                }
+                _ => Some(messages.offline_message.as_deref().unwrap_or("Offline")),
+            };
+            (
+                away.map(String::from),
+                Duration::from_secs(messages.grace_secs.unwrap_or(DEFAULT_AWAY_GRACE_SECS)),
+            )
+        };
+
+        let mut pending = self.pending_away.lock().await;
+
+        // Presences are reported once for every guild shared with the owner
+        if let Some((pending_away, _)) = &*pending {
+            if *pending_away == away {
+                return;
+            }
+        }
+        if let Some((_, handle)) = pending.take() {
+            handle.abort();
+        }
+        if *self.away.lock().await == away {
+            return;
+        }
+
+        // Only going away waits out the grace period, coming back is mirrored right away
+        if away.is_none() {
+            drop(pending);
+            self.set_away(None).await;
+            return;
+        }
+
+        let state = Arc::clone(self);
+        let applied = away.clone();
+        let handle = tokio::spawn(async move {
+            sleep(grace).await;
+            state.set_away(applied).await;
+        });
+        *pending = Some((away, handle));
+    }
+
+    async fn set_away(&self, away: Option<String>) {
+        *self.away.lock().await = away.clone();
+
+        let addrs: Vec<String> = self
+            .config
+            .lock()
+            .await
+            .servers
+            .iter()
+            .map(|server| String::from(&server.address))
+            .collect();
+
+        for addr in addrs {
+            if let Err(why) = self.irc_tx.send(message::BouncerMessage {
+                network: addr,
+                content: away.clone().unwrap_or_default(),
+                ..message::BouncerMessage::new(message::MessageState::AWAY)
+            }) {
+                error!("Unable to set away: {}", why);
+            }
+        }
+    }
+
+    // Connects a puppet for the user if they don't have one yet, returning its nick. None when the
+    // user isn't allowed one
+    async fn puppet(&self, addr: &str, user: &User) -> Option<String> {
+        let config = self.config.lock().await;
+        let puppets = config.puppets.as_ref()?;
+        let server = config.servers.iter().find(|s| s.address == addr)?;
+
+        let configured = puppets
+            .users
+            .iter()
+            .find(|u| u.discord_user_id == user.id.0);
+        if !puppets.users.is_empty() && configured.is_none() {
+            return None;
+        }
+
+        let mut running = self.puppets.lock().await;
+        let key = (String::from(addr), user.id);
+
+        if let Some(puppet) = running.get_mut(&key) {
+            if !puppet.handle.is_finished() {
+                puppet.last_active = Instant::now();
+                return Some(puppet.nick.clone());
+            }
+        }
+
+        let nick = match configured.and_then(|u| u.nick.clone()) {
+            Some(nick) => nick,
+            None => puppet_nick(
+                puppets.nick_format.as_deref().unwrap_or("{name}"),
+                &user.name,
+            ),
+        };
+        let handle = irc::spawn_puppet(
+            server,
+            self.irc_tx.clone(),
+            Arc::clone(&self.storage),
+            user.id.0,
+            nick.clone(),
+            configured
+                .and_then(|u| u.password.as_ref())
+                .map(|password| String::from(password.expose())),
+            self.puppet_nicks.clone(),
+        );
+
+        running.insert(
+            key,
+            Puppet {
+                handle,
+                nick: nick.clone(),
+                last_active: Instant::now(),
+            },
+        );
+        Some(nick)
+    }
+
+    // Quits puppets that have been quiet for too long, or are no longer allowed
+    pub async fn disconnect_idle_puppets(&self) {
+        let (timeout, users) = match &self.config.lock().await.puppets {
+            Some(puppets) => (
+                Duration::from_secs(
+                    puppets
+                        .idle_timeout_secs
+                        .unwrap_or(DEFAULT_PUPPET_IDLE_TIMEOUT_SECS),
+                ),
+                puppets
+                    .users
+                    .iter()
+                    .map(|u| UserId(u.discord_user_id))
+                    .collect::<HashSet<_>>(),
+            ),
+            None => (Duration::from_secs(0), HashSet::new()),
+        };
+
+        let mut running = self.puppets.lock().await;
+        let idle: Vec<(String, UserId)> = running
+            .iter()
+            .filter(|((_, user), puppet)| {
+                puppet.handle.is_finished()
+                    || puppet.last_active.elapsed() >= timeout
+                    || !(users.is_empty() || users.contains(user))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for (addr, user) in idle {
+            if let Some(puppet) = running.remove(&(addr.clone(), user)) {
+                if !puppet.handle.is_finished() {
+                    debug!(%addr, nick = %puppet.nick, "Disconnecting idle puppet");
+                    self.puppet_command(&addr, user, String::from("QUIT :Idle"));
+                }
+            }
+        }
+    }
+
+    // Sends every puppet a QUIT, returning their connections to wait on
+    pub async fn quit_puppets(&self, quit_message: &str) -> Vec<(String, JoinHandle<()>)> {
+        let mut puppets = Vec::new();
+
+        for ((addr, user), puppet) in self.puppets.lock().await.drain() {
+            self.puppet_command(&addr, user, format!("QUIT :{}", quit_message));
+            puppets.push((format!("{} ({})", addr, puppet.nick), puppet.handle));
+        }
+        puppets
+    }
+
+    // Sends a line to every puppet on a network, e.g. to join a newly bridged channel
+    pub async fn send_puppet_command(&self, addr: &str, command: String) {
+        for (_, user) in self.puppets.lock().await.keys().filter(|(a, _)| a == addr) {
+            self.puppet_command(addr, *user, command.clone());
+        }
+    }
+
+    // Puppets of a network that was removed or reconnected. They connect again with the current
+    // settings when their users next talk
+    pub async fn quit_network_puppets(&self, addr: &str, quit_message: &str) {
+        let mut puppets = self.puppets.lock().await;
+        let users: Vec<UserId> = puppets
+            .keys()
+            .filter(|(a, _)| a == addr)
+            .map(|(_, user)| *user)
+            .collect();
+
+        for user in users {
+            puppets.remove(&(String::from(addr), user));
+            self.puppet_command(addr, user, format!("QUIT :{}", quit_message));
+        }
+    }
+
+    // The puppet, if any, that a message relayed from Discord was sent through. None when it
+    // wasn't relayed
+    async fn sender_puppet(&self, message_id: MessageId) -> Option<Option<u64>> {
+        match self
+            .storage
+            .blocking(move |storage| storage.find_by_discord_id(message_id.0))
+            .await
+        {
+            Ok(relayed) => relayed.map(|relayed| relayed.puppet),
+            Err(why) => {
+                error!("Unable to look up who sent message {}: {}", message_id, why);
+                None
+            }
+        }
+    }
+
+    fn puppet_command(&self, addr: &str, user: UserId, command: String) {
+        if let Err(why) = self.irc_tx.send(message::BouncerMessage {
+            network: String::from(addr),
+            content: command,
+            puppet: Some(user.0),
+            ..message::BouncerMessage::new(message::MessageState::COMMAND)
+        }) {
+            error!("Unable to send to puppet on {}: {}", addr, why);
+        }
+    }
+
+    pub fn send_irc_command(
+        &self,
+        addr: &str,
+        channel: &str,
+        command: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.irc_tx.send(message::BouncerMessage {
+            network: String::from(addr),
+            channel: String::from(channel),
+            content: command,
+            ..message::BouncerMessage::new(message::MessageState::COMMAND)
+        })?;
+        Ok(())
+    }
+}
+
+impl TypeMapKey for BridgeState {
+    type Value = Arc<BridgeState>;
+}
+
+async fn bridge_state(ctx: &Context) -> Arc<BridgeState> {
+    ctx.data
+        .read()
+        .await
+        .get::<BridgeState>()
+        .expect("BridgeState missing from client data")
+        .clone()
+}
+
+#[group]
+#[commands(bridge, unbridge, search, export, raw)]
+struct General;
+
+// Normal messages can't be ephemeral, so denials are deleted again after a while instead
+const DENIAL_LIFETIME: Duration = Duration::from_secs(10);
+const TALK_DENIAL_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+// Lines longer than this would be cut off by the server
+const MAX_IRC_LINE_LENGTH: usize = 510;
+
+// Whether the author of a message has at least the required level where it applies. They are told
+// when they don't, rather than being ignored
+async fn authorize(
+    ctx: &Context,
+    state: &BridgeState,
+    msg: &Message,
+    required: AccessLevel,
+    network: Option<&str>,
+    channel: Option<&str>,
+) -> bool {
+    let roles: Vec<u64> = msg.member.as_ref().map_or(Vec::new(), |member| {
+        member.roles.iter().map(|role| role.0).collect()
+    });
+    let level = state
+        .config
+        .lock()
+        .await
+        .access_level(msg.author.id.0, &roles, network, channel);
+
+    if level >= Some(required) {
+        return true;
+    }
+
+    // Commands are answered every time, messages that can't be relayed only now and then
+    if required == AccessLevel::Talk {
+        let mut denials = state.talk_denials.lock().await;
+        let recent = denials
+            .get(&msg.author.id)
+            .is_some_and(|denied| denied.elapsed() < TALK_DENIAL_COOLDOWN);
+        if recent {
+            return false;
+        }
+        denials.insert(msg.author.id, Instant::now());
+    }
+
+    let action = match required {
+        AccessLevel::ReadOnly => "read the logs",
+        AccessLevel::Talk => "talk",
+        AccessLevel::Admin => "manage the bridge",
+    };
+    let place = match (network, channel) {
+        (Some(network), Some(channel)) => format!(" in {} on {}", channel, network),
+        (Some(network), None) => format!(" on {}", network),
+        _ => String::new(),
+    };
+
+    match msg
+        .reply(ctx, format!("You aren't allowed to {}{}", action, place))
+        .await
+    {
+        Ok(reply) => {
+            let http = Arc::clone(&ctx.http);
+            tokio::spawn(async move {
+                sleep(DENIAL_LIFETIME).await;
+                if let Err(why) = reply.channel_id.delete_message(&http, reply.id).await {
+                    debug!("Unable to delete denial: {}", why);
+                }
+            });
+        }
+        Err(why) => warn!("Unable to tell {} about a denial: {}", msg.author.name, why),
+    }
+    false
+}
+
+// Figure out which network a bridge command refers to: an explicit address wins,
+// then the network of the channel the command was sent in, then the only configured network
+async fn resolve_network(
+    state: &BridgeState,
+    channel_id: ChannelId,
+    network: Option<String>,
+) -> Option<String> {
+    let config = state.config.lock().await;
+
+    if let Some(network) = network {
+        return config
+            .servers
+            .iter()
+            .find(|s| s.address == network)
+            .map(|s| String::from(&s.address));
+    }
+
+    if let Some(irc) = state.maps.read().await.discord_irc_map.get(&channel_id) {
+        return Some(String::from(&irc.addr));
+    }
+
+    match config.servers.as_slice() {
+        [server] => Some(String::from(&server.address)),
+        _ => None,
+    }
+}
+
+// Figure out which IRC channel a command refers to: an explicit channel (and network),
+// otherwise the channel the command was sent in
+async fn resolve_channel(
+    state: &BridgeState,
+    channel_id: ChannelId,
+    args: &mut Args,
+) -> Option<(String, String)> {
+    match args.single::<String>().ok() {
+        Some(name) => resolve_network(state, channel_id, args.single::<String>().ok())
+            .await
+            .map(|addr| (addr, name)),
+        None => state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&channel_id)
+            .map(|irc| (String::from(&irc.addr), String::from(&irc.channel))),
+    }
+}
+
+// Discord channel names only allow lowercase letters, digits, '-' and '_'
+fn discord_channel_name(irc_channel: &str) -> String {
+    irc_channel
+        .trim_start_matches(['#', '&'])
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[command]
+#[only_in(guilds)]
+#[usage("<#irc-channel> [network]")]
+async fn bridge(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let state = bridge_state(ctx).await;
+    let name = args.single::<String>()?;
+
+    if !is_channel_name(&name) {
+        msg.reply(ctx, format!("{} is not a valid IRC channel name", name))
+            .await?;
+        return Ok(());
+    }
+
+    let addr = match resolve_network(&state, msg.channel_id, args.single::<String>().ok()).await {
+        Some(addr) => addr,
+        None => {
+            msg.reply(ctx, "Unable to determine the network, please specify one")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if !authorize(ctx, &state, msg, AccessLevel::Admin, Some(&addr), None).await {
+        return Ok(());
+    }
+
+    // Channel names are case-insensitive, and the config rejects ones that only differ in case
+    if state
+        .maps
+        .read()
+        .await
+        .irc_discord_map
+        .keys()
+        .any(|irc| irc.addr == addr && irc.channel.eq_ignore_ascii_case(&name))
+    {
+        msg.reply(ctx, format!("{} on {} is already bridged", name, addr))
+            .await?;
+        return Ok(());
+    }
+
+    let guild_id = msg.guild_id.unwrap();
+    let channel = guild_id
+        .create_channel(&ctx.http, |c| {
+            c.name(discord_channel_name(&name)).kind(ChannelType::Text)
+        })
+        .await?;
+
+    // Undone when any step fails, so no half-bridged channel is left behind
+    let bridged: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        let webhook = channel.id.create_webhook(&ctx.http, &name).await?;
+
+        let irc_channel = IRCChannel {
+            name: String::from(&name),
+            discord_channel: channel.id.0,
+            webhook_url: Secret::new(webhook.url()?),
+            access: Vec::new(),
+        };
+
+        state
+            .maps
+            .write()
+            .await
+            .insert_channel(&addr, &irc_channel)?;
+
+        let mut config = state.config.lock().await;
+        let saved = match config.server_mut(&addr) {
+            Some(server) => {
+                server.channels.push(irc_channel);
+                match config.validate() {
+                    Ok(()) => config.save(&state.config_path),
+                    Err(problems) => Err(problems.join(", ").into()),
+                }
+            }
+            // Removed by a reload in the meantime
+            None => Err(format!("{} is no longer configured", addr).into()),
+        };
+
+        if saved.is_err() {
+            if let Some(server) = config.server_mut(&addr) {
+                server.channels.retain(|c| c.name != name);
+            }
+            state.maps.write().await.remove_channel(&addr, &name);
+        }
+        saved
+    }
+    .await;
+
+    if let Err(why) = bridged {
+        if let Err(why) = channel.id.delete(&ctx.http).await {
+            warn!("Unable to delete {}: {}", channel.id, why);
+        }
+        return Err(why);
+    }
+
+    state.send_irc_command(&addr, &name, format!("JOIN {}", name))?;
+    state
+        .send_puppet_command(&addr, format!("JOIN {}", name))
+        .await;
+
+    msg.reply(
+        ctx,
+        format!("Bridged {} on {} to <#{}>", name, addr, channel.id),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[usage("[#irc-channel] [network]")]
+async fn unbridge(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let state = bridge_state(ctx).await;
+
+    let (addr, name) = match resolve_channel(&state, msg.channel_id, &mut args).await {
+        Some(target) => target,
+        None => {
+            msg.reply(ctx, "Unable to determine which channel to unbridge")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if !authorize(
+        ctx,
+        &state,
+        msg,
+        AccessLevel::Admin,
+        Some(&addr),
+        Some(&name),
+    )
+    .await
+    {
+        return Ok(());
+    }
+
+    let removed = {
+        let mut config = state.config.lock().await;
+        let server = match config.server_mut(&addr) {
+            Some(server) => server,
+            None => {
+                msg.reply(ctx, format!("{} is no longer configured", addr))
+                    .await?;
+                return Ok(());
+            }
+        };
+        let removed = match server
+            .channels
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(&name))
+        {
+            Some(pos) => server.channels.remove(pos),
+            None => {
+                msg.reply(ctx, format!("{} on {} is not bridged", name, addr))
+                    .await?;
+                return Ok(());
+            }
+        };
+        config.save(&state.config_path)?;
+        removed
+    };
+    // As it was bridged, rather than as it was typed
+    let name = removed.name.clone();
+
+    state.maps.write().await.remove_channel(&addr, &name);
+    state.send_irc_command(&addr, &name, format!("PART {}", name))?;
+    state
+        .send_puppet_command(&addr, format!("PART {}", name))
+        .await;
+
+    // Deleting the channel also deletes its webhook
+    let discord_channel = ChannelId(removed.discord_channel);
+    discord_channel.delete(&ctx.http).await?;
+
+    if discord_channel != msg.channel_id {
+        msg.reply(ctx, format!("Unbridged {} on {}", name, addr))
+            .await?;
+    }
+    Ok(())
+}
+
+const SEARCH_PAGE_SIZE: usize = 10;
+const SEARCH_EXCERPT_LENGTH: usize = 200;
+
+// Dates are taken as midnight UTC
+fn parse_date(date: &str) -> Option<SystemTime> {
+    let midnight = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()?
+        .and_hms_opt(0, 0, 0)?
+        .and_utc();
+    Some(midnight.into())
+}
+
+#[command]
+#[only_in(guilds)]
+#[usage(
+    "[nick:<nick>] [channel:<#channel>] [network:<network>] [after:YYYY-MM-DD] [before:YYYY-MM-DD] [page:<n>] [text]"
+)]
+async fn search(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let state = bridge_state(ctx).await;
+    let mut query = SearchQuery {
+        limit: SEARCH_PAGE_SIZE,
+        ..Default::default()
+    };
+    let mut page = 1;
+    let mut words = Vec::new();
+
+    for arg in args.raw() {
+        match arg.split_once(':') {
+            Some(("nick", nick)) => query.nick = Some(nick.to_string()),
+            Some(("channel", channel)) => query.channel = Some(channel.to_string()),
+            Some(("network", network)) => query.network = Some(network.to_string()),
+            Some((bound @ "after", date)) | Some((bound @ "before", date)) => {
+                let time = match parse_date(date) {
+                    Some(time) => time,
+                    None => {
+                        msg.reply(ctx, format!("Invalid date {}, expected YYYY-MM-DD", date))
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                match bound {
+                    "after" => query.since = Some(time),
+                    // Include the whole of the given day
+                    _ => query.until = Some(time + Duration::from_secs(24 * 60 * 60)),
+                }
+            }
+            Some(("page", n)) => page = n.parse::<usize>().unwrap_or(1).max(1),
+            _ => words.push(arg),
+        }
+    }
+
+    if !words.is_empty() {
+        query.text = Some(words.join(" "));
+    }
+    // Pages past the end just find nothing, however far past it they are
+    query.offset = (page - 1).saturating_mul(SEARCH_PAGE_SIZE);
+
+    if !authorize(
+        ctx,
+        &state,
+        msg,
+        AccessLevel::ReadOnly,
+        query.network.as_deref(),
+        query.channel.as_deref(),
+    )
+    .await
+    {
+        return Ok(());
+    }
+
+    let (results, total) = state
+        .storage
+        .blocking(move |storage| storage.search(&query))
+        .await?;
+
+    if results.is_empty() {
+        msg.reply(ctx, "No messages found").await?;
+        return Ok(());
+    }
+
+    let guild_id = msg.guild_id.unwrap();
+    let pages = total.div_ceil(SEARCH_PAGE_SIZE);
+
+    let lines: Vec<String> = results
+        .iter()
+        .map(|result| {
+            let unix = result
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+
+            let mut excerpt: String = result.content.chars().take(SEARCH_EXCERPT_LENGTH).collect();
+            if excerpt.len() < result.content.len() {
+                excerpt.push('…');
+            }
+
+            // Only messages that made it to Discord can be jumped to
+            let jump = match (result.discord_channel_id, result.discord_message_id) {
+                (Some(channel), Some(message)) => format!(
+                    " [jump](https://discord.com/channels/{}/{}/{})",
+                    guild_id, channel, message
+                ),
+                _ => String::new(),
+            };
+
+            format!(
+                "<t:{}:f> **{}** in {} ({}): {}{}",
+                unix, result.nick, result.channel, result.network, excerpt, jump
+            )
+        })
+        .collect();
+
+    let footer = match page < pages {
+        true => format!("Page {} of {}, add page:{} for more", page, pages, page + 1),
+        false => format!("Page {} of {}", page, pages),
+    };
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title(format!("{} messages found", total))
+                    .description(lines.join("\n"))
+                    .footer(|f| f.text(footer))
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+// Discord rejects larger attachments from bots in servers without boosts
+const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+#[command]
+#[only_in(guilds)]
+#[usage("<from YYYY-MM-DD> [to YYYY-MM-DD] [#irc-channel] [network]")]
+async fn export(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let state = bridge_state(ctx).await;
+
+    let from = match args.single::<NaiveDate>() {
+        Ok(from) => from,
+        Err(_) => {
+            msg.reply(ctx, "Expected a start date as YYYY-MM-DD")
+                .await?;
+            return Ok(());
+        }
+    };
+    let to = args.single::<NaiveDate>().unwrap_or(from);
+
+    if to < from {
+        msg.reply(ctx, "The end date must not be before the start date")
+            .await?;
+        return Ok(());
+    }
+
+    let (addr, name) = match resolve_channel(&state, msg.channel_id, &mut args).await {
+        Some(target) => target,
+        None => {
+            msg.reply(ctx, "Unable to determine which channel to export")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if !authorize(
+        ctx,
+        &state,
+        msg,
+        AccessLevel::ReadOnly,
+        Some(&addr),
+        Some(&name),
+    )
+    .await
+    {
+        return Ok(());
+    }
+
+    let archive = {
+        let (logs_dir, addr, name) = (state.logs_dir.clone(), addr.clone(), name.clone());
+        task::spawn_blocking(move || {
+            textlog::export(&logs_dir, &addr, &name, from, to, MAX_ATTACHMENT_SIZE)
+        })
+        .await??
+    };
+
+    let archive = match archive {
+        textlog::Export::Archive(archive) => archive,
+        textlog::Export::Empty => {
+            msg.reply(
+                ctx,
+                format!(
+                    "No logs for {} on {} between {} and {}",
+                    name, addr, from, to
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+        textlog::Export::TooLarge => {
+            msg.reply(
+                ctx,
+                "The logs are too large to upload, try a shorter date range",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let filename = format!("{}_{}_{}.zip", textlog::file_name(&name), from, to);
+    msg.channel_id
+        .send_files(
+            &ctx.http,
+            vec![(archive.as_slice(), filename.as_str())],
+            |m| {
+                m.content(format!(
+                    "Logs for {} on {} from {} to {}",
+                    name, addr, from, to
+                ))
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+// Sends a line as is to the network of the channel it was sent in
+#[command]
+#[only_in(guilds)]
+#[usage("<line>")]
+async fn raw(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let state = bridge_state(ctx).await;
+    let line = args.rest().trim();
+
+    if line.is_empty() || line.contains(['\r', '\n']) || line.len() > MAX_IRC_LINE_LENGTH {
+        msg.reply(
+            ctx,
+            format!(
+                "Expected a single line of at most {} bytes",
+                MAX_IRC_LINE_LENGTH
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let addr = match resolve_network(&state, msg.channel_id, None).await {
+        Some(addr) => addr,
+        None => {
+            msg.reply(
+                ctx,
+                "Unable to determine the network, use a bridged channel",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    // Raw lines reach the whole network, so channel rules don't count
+    if !authorize(ctx, &state, msg, AccessLevel::Admin, Some(&addr), None).await {
+        return Ok(());
+    }
+
+    state.send_irc_command(&addr, "", String::from(line))?;
+    msg.reply(ctx, format!("Sent to {}", addr)).await?;
+    Ok(())
+}
+
+#[hook]
+async fn report_command_error(
+    ctx: &Context,
+    msg: &Message,
+    command_name: &str,
+    result: CommandResult,
+) {
+    if let Err(why) = result {
+        let _ = msg
+            .reply(ctx, format!("/{} failed: {}", command_name, why))
+            .await;
+    }
+}
+
+// Append any file attachments to allow for things like image uploads, etc. to be sent
+fn relay_content(content: &str, attachments: &[Attachment]) -> String {
+    let mut content = String::from(content);
+    for attachment in attachments {
+        content.push_str(&format!(" {}", attachment.url));
+    }
+    content
+}
+
+// Commands are handled by the framework, so they should not be relayed to IRC
+fn is_bouncer_command(content: &str) -> bool {
+    content
+        .strip_prefix('/')
+        .and_then(|c| c.split_whitespace().next())
+        .is_some_and(|name| {
+            GENERAL_GROUP
+                .options
+                .commands
+                .iter()
+                .any(|cmd| cmd.options.names.contains(&name))
+        })
+}
+
+// Undeliverable messages kept around for inspection, oldest are dropped first
+const MAX_DEAD_LETTERS: usize = 1000;
+const REPLY_EXCERPT_LENGTH: usize = 100;
+const DEFAULT_LATE_DELIVERY_THRESHOLD_SECS: u64 = 60;
+const DEFAULT_AWAY_GRACE_SECS: u64 = 30;
+const DEFAULT_AVATAR_URL: &str = "https://i.imgur.com/4amDEwM.jpg";
+// Discord rejects longer webhook usernames
+const MAX_USERNAME_LENGTH: usize = 80;
+const MAX_TOPIC_LENGTH: usize = 1024;
+// The audit log action type of channel updates
+const CHANNEL_UPDATE_ACTION: u8 = 11;
+// Discord shows typing for 10 seconds, so refresh it a little before then
+const TYPING_BROADCAST_INTERVAL: Duration = Duration::from_secs(8);
+const DEFAULT_PUPPET_IDLE_TIMEOUT_SECS: u64 = 60 * 60;
+// Short enough for the NICKLEN of most networks
+const MAX_PUPPET_NICK_LENGTH: usize = 16;
+
+async fn reply_quote(ctx: &Context, replied: &RelayedMessage) -> String {
+    let mut excerpt: String = replied
+        .content
+        .chars()
+        .take(REPLY_EXCERPT_LENGTH)
+        .map(|c| if c == '\n' { ' ' } else { c })
+        .collect();
+    if excerpt.len() < replied.content.len() {
+        excerpt.push('…');
+    }
+
+    let link = match (replied.discord_channel_id, replied.discord_message_id) {
+        (Some(channel_id), Some(message_id)) => {
+            ctx.cache.guild_channel(channel_id).await.map(|channel| {
+                format!(
+                    "https://discord.com/channels/{}/{}/{}",
+                    channel.guild_id, channel_id, message_id
+                )
+            })
+        }
+        _ => None,
+    };
+
+    match link {
+        Some(link) => format!("> [Replying to {}]({}): {}", replied.nick, link, excerpt),
+        None => format!("> Replying to **{}**: {}", replied.nick, excerpt),
+    }
+}
+
+fn webhook_username(
+    format: Option<&str>,
+    nick: &str,
+    prefix: Option<char>,
+    network: &str,
+    general: bool,
+) -> String {
+    let format = format.unwrap_or("{nick}");
+    let host = network.split(':').next().unwrap_or(network);
+
+    // The nick goes in last, since nicks can contain braces
+    let mut username = format
+        .replace("{prefix}", &prefix.map(String::from).unwrap_or_default())
+        .replace("{network}", host)
+        .replace("{nick}", nick);
+
+    // The general channel gets messages from every channel of a network
+    if general && !format.contains("{network}") {
+        username = format!("{} on {}", username, network);
+    }
+    username.chars().take(MAX_USERNAME_LENGTH).collect()
+}
+
+// Fills in the nick format and keeps only what IRC allows in a nick
+fn puppet_nick(format: &str, name: &str) -> String {
+    let mut nick: String = format
+        .replace("{name}", name)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || "-_[]\\^{}|`".contains(*c))
+        .take(MAX_PUPPET_NICK_LENGTH)
+        .collect();
+
+    // Nicks can't start with a digit or a dash
+    if nick.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        nick.insert(0, '_');
+        nick.truncate(MAX_PUPPET_NICK_LENGTH);
+    }
+    if nick.is_empty() {
+        nick = String::from("discord");
+    }
+    nick
+}
 
-use lazy_static::lazy_static;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+fn avatar_url(avatars: Option<&AvatarConfig>, nick: &str, account: Option<&str>) -> String {
+    let avatars = match avatars {
+        Some(avatars) => avatars,
+        None => return String::from(DEFAULT_AVATAR_URL),
+    };
 
-#[derive(PartialEq, Eq, Hash)]
-struct IRCServer {
-    addr: String,
-    channel: String,
+    // IRC names are case-insensitive
+    let by_name = |urls: &HashMap<String, String>, name: &str| {
+        urls.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, url)| String::from(url))
+    };
+
+    account
+        .and_then(|account| by_name(&avatars.accounts, account))
+        .or_else(|| by_name(&avatars.nicks, nick))
+        .or_else(|| {
+            avatars.identicon_base_url.as_ref().map(|base| {
+                format!(
+                    "{}/avatars/{:016x}.png",
+                    base.trim_end_matches('/'),
+                    avatar::nick_hash(nick)
+                )
+            })
+        })
+        .or_else(|| avatars.default_url.clone())
+        .unwrap_or_else(|| String::from(DEFAULT_AVATAR_URL))
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct IRCChannel {
-    pub name: String,
-    discord_channel: u64,
-    webhook_url: String,
+async fn relay_to_discord(
+    ctx: &Context,
+    state: &BridgeState,
+    owner_id: UserId,
+    cmd: message::BouncerMessage,
+) {
+    // History playback can overlap with what was relayed before the bouncer went away
+    if let Some(msgid) = &cmd.msgid {
+        match state
+            .storage
+            .blocking({
+                let (network, msgid) = (cmd.network.clone(), msgid.clone());
+                move |storage| storage.has_msgid(&network, &msgid)
+            })
+            .await
+        {
+            Ok(true) => {
+                debug!("Skipping already relayed message {}", msgid);
+                return;
+            }
+            Ok(false) => {}
+            Err(why) => error!("Unable to look up message {}: {}", msgid, why),
+        }
+    }
+
+    let received = SystemTime::now();
+    let original = cmd.clone();
+    let mut content = cmd.content;
+    let mut lookup = IRCServer {
+        addr: String::from(&cmd.network),
+        channel: cmd.channel,
+    };
+
+    let id;
+    let token;
+    let general;
+
+    {
+        let maps = state.maps.read().await;
+        let discord;
+
+        if let Some(discord2) = maps.irc_discord_map.get(&lookup) {
+            general = false;
+            discord = discord2;
+        } else {
+            // Forward to the general channel
+            lookup.channel = "".to_string();
+            // Messages from a network removed by a reload can still be queued
+            discord = match maps.irc_discord_map.get(&lookup) {
+                Some(discord) => discord,
+                None => {
+                    warn!(
+                        "Dropping message from {}, which is no longer bridged",
+                        lookup.addr
+                    );
+                    return;
+                }
+            };
+            general = true;
+        }
+
+        id = discord.webhook_id;
+        token = String::from(&discord.webhook_token);
+    }
+
+    let (user, avatar_url) = {
+        let config = state.config.lock().await;
+        (
+            webhook_username(
+                config.username_format.as_deref(),
+                &cmd.user,
+                cmd.prefix,
+                &cmd.network,
+                general,
+            ),
+            avatar_url(config.avatars.as_ref(), &cmd.user, cmd.account.as_deref()),
+        )
+    };
+
+    Span::current().record("webhook", id);
+
+    let webhook = match ctx.http.get_webhook_with_token(id, &token).await {
+        Ok(webhook) => webhook,
+        Err(why) => {
+            error!("Unable to get webhook {}: {}", id, why);
+            add_dead_letter(state, original).await;
+            return;
+        }
+    };
+
+    lazy_static! {
+        static ref ACTION_RE: Regex = Regex::new("\x01ACTION ([^\x01]+)\x01").unwrap();
+    }
+
+    // Handle IRC actions (e.g. /me)
+
+    if let Some(caps) = ACTION_RE.captures(&content) {
+        content = format!("*{}*", caps.get(1).unwrap().as_str())
+    }
+
+    // Webhooks can't backdate messages, so late ones (played back, or held up by a reconnect
+    // burst or retries) show when they were originally sent
+    if let Some(time) = cmd.time {
+        let threshold = state
+            .config
+            .lock()
+            .await
+            .late_delivery_threshold_secs
+            .unwrap_or(DEFAULT_LATE_DELIVERY_THRESHOLD_SECS);
+        let delay = received.duration_since(time).unwrap_or_default();
+
+        if cmd.playback || delay > Duration::from_secs(threshold) {
+            let unix = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+            // Just the time for today's messages, the full date for anything older
+            content = match delay < Duration::from_secs(24 * 60 * 60) {
+                true => format!("<t:{}:T> {}", unix, content),
+                false => format!("<t:{}:f> {}", unix, content),
+            };
+        }
+    }
+
+    content = match cmd.ping {
+        true => format!("<@{}> {}", owner_id, content),
+        false => content,
+    };
+
+    // Webhooks can't send real replies, so quote the message being replied to with a link to it
+    if let Some(msgid) = cmd.reply_to.as_ref().and_then(|reply| reply.msgid.as_ref()) {
+        match state
+            .storage
+            .blocking({
+                let (network, msgid) = (cmd.network.clone(), msgid.clone());
+                move |storage| storage.find_by_msgid(&network, &msgid)
+            })
+            .await
+        {
+            Ok(Some(replied)) => {
+                content = format!("{}\n{}", reply_quote(ctx, &replied).await, content);
+            }
+            Ok(None) => debug!("Replied message {} was never relayed", msgid),
+            Err(why) => error!("Unable to look up replied message {}: {}", msgid, why),
+        }
+    }
+
+    let mut transmission_attempts = 0;
+    let mut discord_message_id = None;
+    let mut discord_channel_id = None;
+
+    while transmission_attempts < 3 {
+        let started = Instant::now();
+        // Waiting for the created message gives us its id for the message log
+        let result = webhook
+            .execute(&ctx.http, true, |w| {
+                w.content(&content).username(&user).avatar_url(&avatar_url)
+            })
+            .await;
+        METRICS.webhook_latency.observe(started.elapsed());
+
+        match result {
+            Ok(message) => {
+                discord_message_id = message.as_ref().map(|m| m.id.0);
+                discord_channel_id = message.as_ref().map(|m| m.channel_id.0);
+                break;
+            }
+            Err(why) => {
+                if let SerenityError::Http(http_error) = &why {
+                    if http_error.status_code().map(|s| s.as_u16()) == Some(429) {
+                        METRICS.webhook_rate_limited.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+
+                transmission_attempts += 1;
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    let recorded = original.clone();
+    if let Err(why) = state
+        .storage
+        .blocking(move |storage| {
+            storage.record(&LoggedMessage {
+                network: &recorded.network,
+                channel: &recorded.channel,
+                nick: &recorded.user,
+                content: &recorded.content,
+                direction: recorded.state,
+                timestamp: recorded.time.unwrap_or(received),
+                msgid: recorded.msgid.as_deref(),
+                discord_message_id,
+                discord_channel_id,
+                puppet: None,
+            })
+        })
+        .await
+    {
+        error!("Unable to record message: {}", why);
+    }
+
+    // TODO: Should we re-transmit this message?
+    if transmission_attempts == 3 {
+        error!("Failed to send webhook {}", logging::redact(&content));
+        add_dead_letter(state, original).await;
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct IRCServerConfig {
-    pub address: String,
-    pub tls: bool,
-    pub nick: String,
-    pub password: Option<String>,
-    pub general_webhook: String,
-    pub channels: Vec<IRCChannel>,
+async fn add_dead_letter(state: &BridgeState, message: message::BouncerMessage) {
+    METRICS.delivery_failures.fetch_add(1, Ordering::Relaxed);
+
+    let mut dead_letters = state.dead_letters.lock().await;
+    if dead_letters.len() == MAX_DEAD_LETTERS {
+        dead_letters.pop_front();
+    }
+    dead_letters.push_back(message);
+    METRICS
+        .dead_letters
+        .store(dead_letters.len() as u64, Ordering::Relaxed);
 }
 
-struct DiscordChannel {
-    webhook_id: u64,
-    webhook_token: String,
+// Deletes what a message redacted on IRC was relayed as
+async fn delete_from_discord(ctx: &Context, state: &BridgeState, cmd: message::BouncerMessage) {
+    let msgid = match &cmd.msgid {
+        Some(msgid) => msgid,
+        None => return,
+    };
+
+    let discord_message_id = match state
+        .storage
+        .blocking({
+            let (network, msgid) = (cmd.network.clone(), msgid.clone());
+            move |storage| storage.redact(&network, &msgid)
+        })
+        .await
+    {
+        Ok(Some(relayed)) => match relayed.discord_message_id {
+            Some(id) => id,
+            None => return,
+        },
+        Ok(None) => {
+            debug!("Redacted message {} was never relayed", msgid);
+            return;
+        }
+        Err(why) => {
+            error!("Unable to look up redacted message {}: {}", msgid, why);
+            return;
+        }
+    };
+
+    // Relayed through the channel's webhook, or the general one
+    let webhook = {
+        let maps = state.maps.read().await;
+        let mut lookup = IRCServer {
+            addr: String::from(&cmd.network),
+            channel: String::from(&cmd.channel),
+        };
+
+        if !maps.irc_discord_map.contains_key(&lookup) {
+            lookup.channel = "".to_string();
+        }
+        maps.irc_discord_map
+            .get(&lookup)
+            .map(|discord| (discord.webhook_id, String::from(&discord.webhook_token)))
+    };
+
+    if let Some((id, token)) = webhook {
+        if let Err(why) = ctx
+            .http
+            .delete_webhook_message(id, &token, discord_message_id)
+            .await
+        {
+            error!("Unable to delete redacted message {}: {}", msgid, why);
+        }
+    }
+}
+
+// Adds a reaction from IRC to what the message was relayed as
+async fn react_in_discord(ctx: &Context, state: &BridgeState, cmd: message::BouncerMessage) {
+    let msgid = match cmd.reply_to.and_then(|reply| reply.msgid) {
+        Some(msgid) => msgid,
+        None => return,
+    };
+
+    let relayed = match state
+        .storage
+        .blocking({
+            let (network, msgid) = (cmd.network.clone(), msgid.clone());
+            move |storage| storage.find_by_msgid(&network, &msgid)
+        })
+        .await
+    {
+        Ok(Some(relayed)) => relayed,
+        Ok(None) => {
+            debug!("Reacted message {} was never relayed", msgid);
+            return;
+        }
+        Err(why) => {
+            error!("Unable to look up reacted message {}: {}", msgid, why);
+            return;
+        }
+    };
+
+    if let (Some(message_id), Some(channel_id)) =
+        (relayed.discord_message_id, relayed.discord_channel_id)
+    {
+        // IRC clients can react with any text, Discord only takes emoji
+        if let Err(why) = ctx
+            .http
+            .create_reaction(channel_id, message_id, &ReactionType::Unicode(cmd.content))
+            .await
+        {
+            debug!("Unable to add reaction from {}: {}", cmd.user, why);
+        }
+    }
+}
+
+// Mirrors an IRC topic onto the mapped Discord channel, and announces changes made on IRC
+async fn relay_topic(
+    ctx: &Context,
+    state: &BridgeState,
+    owner_id: UserId,
+    cmd: message::BouncerMessage,
+) {
+    let channel_id = state
+        .maps
+        .read()
+        .await
+        .discord_channel(&cmd.network, &cmd.channel);
+
+    if let Some(channel_id) = channel_id {
+        let topic: String = cmd.content.chars().take(MAX_TOPIC_LENGTH).collect();
+        let current = ctx
+            .cache
+            .guild_channel_field(channel_id, |channel| channel.topic.clone())
+            .await
+            .flatten();
+
+        if current.as_deref().unwrap_or_default() != topic {
+            // Topic edits are heavily rate limited, so they mustn't hold up delivery
+            let http = Arc::clone(&ctx.http);
+            tokio::spawn(async move {
+                if let Err(why) = channel_id.edit(&http, |c| c.topic(&topic)).await {
+                    warn!("Unable to set the topic of {}: {}", channel_id, why);
+                }
+            });
+        }
+    }
+
+    if !cmd.playback {
+        let content = format!("*changed the topic to:* {}", cmd.content);
+        relay_to_discord(
+            ctx,
+            state,
+            owner_id,
+            message::BouncerMessage {
+                state: message::MessageState::INCOMING,
+                content,
+                ..cmd
+            },
+        )
+        .await;
+    }
+}
+
+// The bot can only show itself typing, and not stop early, so only active statuses are shown.
+// Discord is asked at most once per interval for each channel.
+async fn show_typing(
+    ctx: &Context,
+    state: &BridgeState,
+    shown: &mut HashMap<ChannelId, Instant>,
+    cmd: message::BouncerMessage,
+) {
+    if cmd.content != "active" {
+        return;
+    }
+
+    let channel_id = match state
+        .maps
+        .read()
+        .await
+        .discord_channel(&cmd.network, &cmd.channel)
+    {
+        Some(channel_id) => channel_id,
+        None => return,
+    };
+
+    let now = Instant::now();
+    shown.retain(|_, at| now.duration_since(*at) < TYPING_BROADCAST_INTERVAL);
+    if shown.contains_key(&channel_id) {
+        return;
+    }
+    shown.insert(channel_id, now);
+
+    if let Err(why) = channel_id.broadcast_typing(&ctx.http).await {
+        debug!("Unable to show {} typing: {}", cmd.user, why);
+    }
 }
 
-#[group]
-struct General;
 struct Handler {
-    is_loop_running: AtomicBool,
-    irc_tx: Sender<message::BouncerMessage>,
-    discord_irc_map: HashMap<ChannelId, IRCServer>,
-    irc_discord_map: Arc<HashMap<IRCServer, DiscordChannel>>,
+    state: Arc<BridgeState>,
     discord_user_id: UserId,
 }
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn cache_ready(&self, ctx: Context, _guilds: Vec<GuildId>) {
-        if !self.is_loop_running.load(Ordering::Relaxed) {
-            self.is_loop_running.swap(true, Ordering::Relaxed);
+    async fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        if !self.state.discord_ready.load(Ordering::Relaxed) {
+            self.state.discord_ready.swap(true, Ordering::Relaxed);
+
+            // Offline members are left out of guild presences
+            let mut status = OnlineStatus::Offline;
+            for guild_id in guilds {
+                if let Some(presence) = ctx
+                    .cache
+                    .guild_field(guild_id, |guild| {
+                        guild.presences.get(&self.discord_user_id).map(|p| p.status)
+                    })
+                    .await
+                    .flatten()
+                {
+                    status = presence;
+                    break;
+                }
+            }
+            self.state.presence_changed(status).await;
 
             let ctx = ctx.clone();
-            let mut rx = self.irc_tx.subscribe();
+            let mut rx = self.state.irc_tx.subscribe();
 
-            let irc_discord_map = self.irc_discord_map.clone();
-            let owner_id = self.discord_user_id.clone();
+            let state = self.state.clone();
+            let owner_id = self.discord_user_id;
 
             tokio::spawn(async move {
-                while let Ok(cmd) = rx.recv().await {
-                    if cmd.state == message::MessageState::INCOMING {
-                        let mut content = cmd.content;
-                        let mut lookup = IRCServer {
-                            addr: String::from(&cmd.network),
-                            channel: cmd.channel,
-                        };
-
-                        let discord;
-                        let user;
-
-                        if let Some(discord2) = irc_discord_map.get(&lookup) {
-                            user = cmd.user;
-                            discord = discord2;
-                        } else {
-                            // Forward to the general channel
-                            lookup.channel = "".to_string();
-                            discord = irc_discord_map.get(&lookup).unwrap();
-                            user = format!("{} on {}", cmd.user, cmd.network);
-                        }
-
-                        let id = discord.webhook_id;
-                        let token = &discord.webhook_token;
-                        let webhook = ctx.http.get_webhook_with_token(id, &token).await.unwrap();
-
-                        lazy_static! {
-                            static ref ACTION_RE: Regex =
-                                Regex::new("\x01ACTION ([^\x01]+)\x01").unwrap();
-                        }
+                let mut typing = HashMap::new();
 
-                        // Handle IRC actions (e.g. /me)
-
-                        if let Some(caps) = ACTION_RE.captures(&content) {
-                            content = format!("*{}*", caps.get(1).unwrap().as_str())
+                loop {
+                    let cmd = match rx.recv().await {
+                        Ok(cmd) => cmd,
+                        Err(RecvError::Lagged(skipped)) => {
+                            METRICS
+                                .broadcast_lagged
+                                .fetch_add(skipped, Ordering::Relaxed);
+                            warn!(
+                                "Delivery loop fell behind, {} messages were dropped",
+                                skipped
+                            );
+                            continue;
                         }
+                        Err(RecvError::Closed) => break,
+                    };
 
-                        content = match cmd.ping {
-                            true => format!("<@{}> {}", owner_id, content),
-                            false => content,
-                        };
-
-                        let mut transmission_attempts = 0;
-
-                        while transmission_attempts < 3 {
-                            if let Ok(_) = webhook
-                                .execute(&ctx.http, false, |w| {
-                                    w.content(&content)
-                                        .username(&user)
-                                        .avatar_url("https://i.imgur.com/4amDEwM.jpg")
-                                })
-                                .await
-                            {
-                                break;
-                            } else {
-                                {
-                                    transmission_attempts += 1;
-                                    sleep(Duration::from_millis(100)).await;
-                                }
-                            }
-                        }
+                    if cmd.state == message::MessageState::SHUTDOWN {
+                        state.drained.notify_one();
+                        break;
+                    }
 
-                        // TODO: Should we re-transmit this message?
-                        if transmission_attempts == 3 {
-                            println!("ERROR: Failed to send webhook {}", content);
-                        }
+                    if cmd.state == message::MessageState::INCOMING {
+                        let span = info_span!(
+                            "discord_channel",
+                            network = %cmd.network,
+                            channel = %cmd.channel,
+                            webhook = field::Empty
+                        );
+                        relay_to_discord(&ctx, &state, owner_id, cmd)
+                            .instrument(span)
+                            .await;
+                    } else if cmd.state == message::MessageState::REDACT {
+                        delete_from_discord(&ctx, &state, cmd).await;
+                    } else if cmd.state == message::MessageState::REACTION {
+                        react_in_discord(&ctx, &state, cmd).await;
+                    } else if cmd.state == message::MessageState::TOPIC {
+                        relay_topic(&ctx, &state, owner_id, cmd).await;
+                    } else if cmd.state == message::MessageState::TYPINGSTATUS {
+                        show_typing(&ctx, &state, &mut typing, cmd).await;
                     }
                 }
             });
@@ -148,123 +1676,552 @@ impl EventHandler for Handler {
     }
 
     async fn message(&self, ctx: Context, msg: Message) {
-        // Don't forward messages from non-owner
-        if msg.author.id != self.discord_user_id {
+        // Relayed IRC messages come from webhooks, and other bots aren't relayed either
+        if msg.author.bot || msg.webhook_id.is_some() || is_bouncer_command(&msg.content) {
             return;
         }
+        let owner = msg.author.id == self.discord_user_id;
+        let mut content = relay_content(&msg.content, &msg.attachments);
 
-        println!("Channel id: {}", &msg.channel_id);
-        let mut content = String::new();
+        // Replies to relayed messages point at the original IRC message, and otherwise at whoever sent it
+        let reply_to = match msg.referenced_message.as_ref() {
+            Some(referenced) => {
+                let id = referenced.id.0;
+                let relayed = self
+                    .state
+                    .storage
+                    .blocking(move |storage| storage.find_by_discord_id(id))
+                    .await
+                    .unwrap_or_else(|why| {
+                        error!("Unable to look up replied message: {}", why);
+                        None
+                    });
 
-        // Check to see if the messae is a reply
-        // If so, append <name>: to ping them
-        match &msg.message_reference {
-            Some(msg2) => {
-                let msg_ref =
-                    Message::parse(&ctx, &msg, &msg2.message_id.unwrap().as_u64().to_string())
-                        .await
-                        .unwrap();
-                content.push_str(&format!("{}:", msg_ref.author.name));
+                Some(match relayed {
+                    Some(relayed) => message::Reply {
+                        msgid: relayed.msgid,
+                        nick: Some(relayed.nick),
+                    },
+                    None => message::Reply {
+                        msgid: None,
+                        nick: Some(String::from(&referenced.author.name)),
+                    },
+                })
             }
-            None => {}
-        }
+            None => None,
+        };
 
-        content.push_str(&msg.content);
+        // Copied out so the maps aren't locked while waiting on the config below
+        let irc = self
+            .state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&msg.channel_id)
+            .cloned();
 
-        // append any file attachments to allow for things like image uploads, etc. to be sent
-        for attachment in msg.attachments {
-            content.push_str(&format!(" {}", attachment.url));
-        }
+        if let Some(irc) = irc {
+            if !authorize(
+                &ctx,
+                &self.state,
+                &msg,
+                AccessLevel::Talk,
+                Some(&irc.addr),
+                Some(&irc.channel),
+            )
+            .await
+            {
+                return;
+            }
+            debug!(channel_id = %msg.channel_id, owner, "Relaying message");
+
+            let own_nick = self
+                .state
+                .config
+                .lock()
+                .await
+                .servers
+                .iter()
+                .find(|s| s.address == irc.addr)
+                .map_or(String::new(), |s| String::from(&s.nick));
+
+            let (nick, puppet) = match owner {
+                true => (own_nick, None),
+                false => match self.state.puppet(&irc.addr, &msg.author).await {
+                    Some(nick) => (nick, Some(msg.author.id.0)),
+                    // Without a puppet of their own, the bridge says it in their name
+                    None => {
+                        content = format!("<{}> {}", msg.author.name, content);
+                        (own_nick, None)
+                    }
+                },
+            };
+
+            let (addr, channel, recorded_nick, recorded_content) = (
+                irc.addr.clone(),
+                irc.channel.clone(),
+                nick.clone(),
+                content.clone(),
+            );
+            let (message_id, channel_id) = (msg.id.0, msg.channel_id.0);
+            if let Err(why) = self
+                .state
+                .storage
+                .blocking(move |storage| {
+                    storage.record(&LoggedMessage {
+                        network: &addr,
+                        channel: &channel,
+                        nick: &recorded_nick,
+                        content: &recorded_content,
+                        direction: message::MessageState::OUTGOING,
+                        timestamp: SystemTime::now(),
+                        msgid: None,
+                        discord_message_id: Some(message_id),
+                        discord_channel_id: Some(channel_id),
+                        puppet,
+                    })
+                })
+                .await
+            {
+                error!("Unable to record message: {}", why);
+            }
 
-        if let Some(irc) = self.discord_irc_map.get(&msg.channel_id) {
             // TODO: If this method returns an Err, this means we have lost all IRC connections
             // Need to notify user of this
             // Also, the message synchronization should be changed to be 1-1 channels, that way we know specifically what failed
-            self.irc_tx
+            self.state
+                .irc_tx
                 .send(message::BouncerMessage {
                     channel: String::from(&irc.channel),
                     network: String::from(&irc.addr),
-                    user: "".to_string(),
-                    content: content,
-                    state: message::MessageState::OUTGOING,
-                    ping: false,
+                    content,
+                    reply_to,
+                    discord_message_id: Some(msg.id.0),
+                    puppet,
+                    ..message::BouncerMessage::new(message::MessageState::OUTGOING)
                 })
                 .unwrap();
         }
     }
+
+    async fn channel_update(&self, ctx: Context, old: Option<Channel>, new: Channel) {
+        let channel = match new {
+            Channel::Guild(channel) => channel,
+            _ => return,
+        };
+
+        // Something other than the topic changed
+        if let Some(old) = old.and_then(|old| old.guild()) {
+            if old.topic == channel.topic {
+                return;
+            }
+        }
+
+        let irc = self
+            .state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&channel.id)
+            .cloned();
+
+        let irc = match irc {
+            Some(irc) => irc,
+            None => return,
+        };
+
+        let enabled = self
+            .state
+            .config
+            .lock()
+            .await
+            .servers
+            .iter()
+            .find(|s| s.address == irc.addr)
+            .and_then(|s| s.topic_to_irc)
+            .unwrap_or(false);
+
+        if !enabled {
+            return;
+        }
+
+        // Channel updates don't say who made them, and the bot's own topic edits mustn't be sent back
+        let logs = match channel
+            .guild_id
+            .audit_logs(&ctx.http, Some(CHANNEL_UPDATE_ACTION), None, None, Some(10))
+            .await
+        {
+            Ok(logs) => logs,
+            Err(why) => {
+                warn!("Unable to read the audit log: {}", why);
+                return;
+            }
+        };
+
+        let by_owner = logs
+            .entries
+            .values()
+            .filter(|entry| entry.target_id == Some(channel.id.0))
+            .max_by_key(|entry| entry.id)
+            .is_some_and(|entry| {
+                entry.user_id == self.discord_user_id
+                    && entry
+                        .changes
+                        .iter()
+                        .flatten()
+                        .any(|change| change.name == "topic")
+            });
+
+        if !by_owner {
+            return;
+        }
+
+        let mut line = format!("TOPIC {} :", irc.channel);
+        let room = MAX_IRC_LINE_LENGTH.saturating_sub(line.len());
+
+        // Discord allows longer topics than fit on an IRC line
+        let mut taken = 0;
+        for c in channel.topic.unwrap_or_default().chars() {
+            let c = if c == '\r' || c == '\n' { ' ' } else { c };
+            taken += c.len_utf8();
+            if taken > room {
+                break;
+            }
+            line.push(c);
+        }
+
+        if let Err(why) = self.state.send_irc_command(&irc.addr, &irc.channel, line) {
+            error!("Unable to set topic: {}", why);
+        }
+    }
+
+    async fn presence_update(&self, _ctx: Context, event: PresenceUpdateEvent) {
+        if event.presence.user_id == self.discord_user_id {
+            self.state.presence_changed(event.presence.status).await;
+        }
+    }
+
+    async fn typing_start(&self, _ctx: Context, event: TypingStartEvent) {
+        if event.user_id != self.discord_user_id {
+            return;
+        }
+
+        let irc = self
+            .state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&event.channel_id)
+            .cloned();
+
+        if let Some(irc) = irc {
+            if let Err(why) = self.state.irc_tx.send(message::BouncerMessage {
+                channel: String::from(&irc.channel),
+                network: String::from(&irc.addr),
+                ..message::BouncerMessage::new(message::MessageState::TYPING)
+            }) {
+                error!("Unable to relay typing: {}", why);
+            }
+        }
+    }
+
+    async fn reaction_add(&self, _ctx: Context, reaction: Reaction) {
+        if reaction.user_id != Some(self.discord_user_id) {
+            return;
+        }
+
+        let emoji = match reaction.emoji {
+            ReactionType::Unicode(emoji) => emoji,
+            ReactionType::Custom { name, .. } => format!(":{}:", name.unwrap_or_default()),
+            _ => return,
+        };
+
+        let irc = self
+            .state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&reaction.channel_id)
+            .cloned();
+
+        let irc = match irc {
+            Some(irc) => irc,
+            None => return,
+        };
+
+        // Only relayed messages have something to react to on IRC
+        let message_id = reaction.message_id.0;
+        let reacted = match self
+            .state
+            .storage
+            .blocking(move |storage| storage.find_by_discord_id(message_id))
+            .await
+        {
+            Ok(Some(relayed)) => message::Reply {
+                msgid: relayed.msgid,
+                nick: Some(relayed.nick),
+            },
+            Ok(None) => return,
+            Err(why) => {
+                error!("Unable to look up reacted message: {}", why);
+                return;
+            }
+        };
+
+        if let Err(why) = self.state.irc_tx.send(message::BouncerMessage {
+            channel: String::from(&irc.channel),
+            network: String::from(&irc.addr),
+            content: emoji,
+            reply_to: Some(reacted),
+            discord_message_id: Some(reaction.message_id.0),
+            ..message::BouncerMessage::new(message::MessageState::REACT)
+        }) {
+            error!("Unable to relay reaction: {}", why);
+        }
+    }
+
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        let irc = self
+            .state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&channel_id)
+            .cloned();
+
+        let irc = match irc {
+            Some(irc) => irc,
+            None => return,
+        };
+
+        // Deletions don't say who sent the message, so the redaction goes out from whichever
+        // connection the message log says sent it
+        let puppet = match self.state.sender_puppet(deleted_message_id).await {
+            Some(puppet) => puppet,
+            None => return,
+        };
+
+        if let Err(why) = self.state.irc_tx.send(message::BouncerMessage {
+            channel: String::from(&irc.channel),
+            network: String::from(&irc.addr),
+            discord_message_id: Some(deleted_message_id.0),
+            puppet,
+            ..message::BouncerMessage::new(message::MessageState::DELETE)
+        }) {
+            error!("Unable to relay deletion: {}", why);
+        }
+    }
+
+    async fn message_update(
+        &self,
+        _ctx: Context,
+        _old_if_available: Option<Message>,
+        new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // The cached message is complete, the event only carries what changed
+        let (author, content) = match new {
+            Some(msg) => (msg.author.id, relay_content(&msg.content, &msg.attachments)),
+            None => match (event.author, event.content) {
+                (Some(author), Some(content)) => (
+                    author.id,
+                    relay_content(&content, &event.attachments.unwrap_or_default()),
+                ),
+                _ => return,
+            },
+        };
+
+        if is_bouncer_command(&content) {
+            return;
+        }
+
+        let irc = self
+            .state
+            .maps
+            .read()
+            .await
+            .discord_irc_map
+            .get(&event.channel_id)
+            .cloned();
+
+        let irc = match irc {
+            Some(irc) => irc,
+            None => return,
+        };
+
+        // Only the owner's messages and those sent through a puppet can be edited on IRC, from the
+        // connection that sent them. What changed is checked against the message log on the IRC side
+        let puppet = match self.state.sender_puppet(event.id).await {
+            Some(puppet) => puppet,
+            None => return,
+        };
+        let sender = puppet.map_or(self.discord_user_id, UserId);
+        if author != sender {
+            return;
+        }
+
+        debug!(channel_id = %event.channel_id, "Relaying edit");
+        if let Err(why) = self.state.irc_tx.send(message::BouncerMessage {
+            channel: String::from(&irc.channel),
+            network: String::from(&irc.addr),
+            content,
+            discord_message_id: Some(event.id.0),
+            puppet,
+            ..message::BouncerMessage::new(message::MessageState::EDIT)
+        }) {
+            error!("Unable to relay edit: {}", why);
+        }
+    }
 }
 
 fn webhook_from_url(webhook_url: &str) -> Option<DiscordChannel> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r".+/webhooks/([^/]+)/([^/]+)").unwrap();
     }
-    let captures = RE.captures(webhook_url).unwrap();
+    let captures = RE.captures(webhook_url)?;
     Some(DiscordChannel {
-        webhook_id: captures
-            .get(1)
-            .map_or(0, |m| m.as_str().parse::<u64>().unwrap()),
-        webhook_token: captures
-            .get(2)
-            .map_or("".to_string(), |m| m.as_str().to_string()),
+        webhook_id: captures.get(1)?.as_str().parse::<u64>().ok()?,
+        webhook_token: captures.get(2)?.as_str().to_string(),
     })
 }
 
-pub async fn discord_init(
-    token: &str,
-    discord_user_id: u64,
-    servers: Vec<IRCServerConfig>,
-    tx: Sender<message::BouncerMessage>,
-) {
+pub async fn discord_init(state: Arc<BridgeState>) -> Client {
+    let (token, discord_user_id, intents) = {
+        let config = state.config.lock().await;
+        (
+            String::from(config.token.expose()),
+            UserId::from(config.discord_user_id),
+            // Presences are privileged, so only asked for when they are mirrored
+            match config.away {
+                Some(_) => GatewayIntents::non_privileged() | GatewayIntents::GUILD_PRESENCES,
+                None => GatewayIntents::non_privileged(),
+            },
+        )
+    };
+
+    let mut owners = HashSet::new();
+    owners.insert(discord_user_id);
+
     let framework = StandardFramework::new()
-        .configure(|c| c.prefix("/"))
+        .configure(|c| c.prefix("/").owners(owners))
+        .after(report_command_error)
         .group(&GENERAL_GROUP);
 
-    let mut irc_discord_map = HashMap::new();
-    let mut discord_irc_map = HashMap::new();
+    let client = Client::builder(&token)
+        .event_handler(Handler {
+            state: state.clone(),
+            discord_user_id,
+        })
+        .framework(framework)
+        .intents(intents)
+        .await
+        .expect("Error creating discord bot instance");
 
-    for server in servers {
-        for channel in server.channels {
-            irc_discord_map.insert(
-                IRCServer {
-                    addr: String::from(&server.address),
-                    channel: String::from(&channel.name),
-                },
-                webhook_from_url(&channel.webhook_url).unwrap(),
-            );
+    client.data.write().await.insert::<BridgeState>(state);
+    client
+}
 
-            discord_irc_map.insert(
-                ChannelId(channel.discord_channel),
-                IRCServer {
-                    addr: String::from(&server.address),
-                    channel: String::from(&channel.name),
-                },
-            );
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Add general channel for server-messages, DMs, etc.
-        irc_discord_map.insert(
-            IRCServer {
-                addr: String::from(&server.address),
-                channel: "".to_string(),
-            },
-            webhook_from_url(&server.general_webhook).unwrap(),
+    #[test]
+    fn puppet_nick_fills_format() {
+        assert_eq!(puppet_nick("{name}[d]", "alice"), "alice[d]");
+    }
+
+    #[test]
+    fn puppet_nick_drops_invalid_characters() {
+        assert_eq!(puppet_nick("{name}", "Zoë Smith!"), "ZoSmith");
+    }
+
+    #[test]
+    fn puppet_nick_fixes_leading_digit_or_dash() {
+        assert_eq!(puppet_nick("{name}", "1337"), "_1337");
+        assert_eq!(puppet_nick("{name}", "-dash"), "_-dash");
+    }
+
+    #[test]
+    fn puppet_nick_truncates() {
+        let nick = puppet_nick("{name}", "0123456789abcdefghij");
+        assert_eq!(nick, "_0123456789abcde");
+        assert_eq!(nick.len(), MAX_PUPPET_NICK_LENGTH);
+    }
+
+    #[test]
+    fn puppet_nick_falls_back_when_empty() {
+        assert_eq!(puppet_nick("{name}", "日本"), "discord");
+    }
+
+    #[test]
+    fn webhook_username_fills_format() {
+        assert_eq!(
+            webhook_username(
+                Some("{prefix}{nick} ({network})"),
+                "alice",
+                Some('@'),
+                "irc.libera.chat:6697",
+                false
+            ),
+            "@alice (irc.libera.chat)"
+        );
+        assert_eq!(
+            webhook_username(None, "alice", Some('@'), "irc.libera.chat:6697", false),
+            "alice"
         );
     }
 
-    let mut client = Client::builder(token)
-        .event_handler(Handler {
-            is_loop_running: AtomicBool::new(false),
-            irc_tx: tx,
-            discord_irc_map: discord_irc_map,
-            irc_discord_map: Arc::new(irc_discord_map),
-            discord_user_id: UserId::from(discord_user_id),
-        })
-        .framework(framework)
-        .await
-        .expect("Error creating discord bot instance");
+    #[test]
+    fn webhook_username_keeps_braces_in_nicks() {
+        assert_eq!(
+            webhook_username(
+                Some("{nick}!"),
+                "{prefix}",
+                None,
+                "irc.libera.chat:6697",
+                false
+            ),
+            "{prefix}!"
+        );
+    }
 
-    if let Err(why) = client.start().await {
-        println!("An error occurred while running the client: {:?}", why);
-        return;
+    #[test]
+    fn webhook_username_names_network_in_general() {
+        assert_eq!(
+            webhook_username(None, "alice", None, "irc.libera.chat:6697", true),
+            "alice on irc.libera.chat:6697"
+        );
+        assert_eq!(
+            webhook_username(
+                Some("{nick}@{network}"),
+                "alice",
+                None,
+                "irc.libera.chat:6697",
+                true
+            ),
+            "alice@irc.libera.chat"
+        );
+    }
+
+    #[test]
+    fn webhook_username_truncates() {
+        let nick = "é".repeat(100);
+        assert_eq!(
+            webhook_username(None, &nick, None, "irc.libera.chat:6697", false)
+                .chars()
+                .count(),
+            MAX_USERNAME_LENGTH
+        );
     }
 }
@@ -0,0 +1,99 @@
+use serde_json::{json, Value};
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::Ordering;
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+use crate::discord::BridgeState;
+use crate::metrics::METRICS;
+
+// Ready means the Discord gateway is up and every configured network has completed registration
+pub async fn readiness(state: &BridgeState) -> (bool, Value) {
+    let discord_ready = state.discord_ready.load(Ordering::Relaxed);
+    let mut ready = discord_ready;
+    let mut networks = serde_json::Map::new();
+
+    for server in &state.config.lock().await.servers {
+        let metrics = METRICS.network(&server.address);
+        let registered = metrics.registered.load(Ordering::Relaxed);
+        ready &= registered;
+
+        networks.insert(
+            String::from(&server.address),
+            json!({
+                "connected": metrics.connected.load(Ordering::Relaxed),
+                "registered": registered,
+            }),
+        );
+    }
+
+    (
+        ready,
+        json!({
+            "ready": ready,
+            "discord": { "ready": discord_ready },
+            "networks": networks,
+        }),
+    )
+}
+
+fn sd_notify(socket: &UnixDatagram, notify_socket: &str, message: &str) {
+    // Abstract sockets are given with a leading '@'
+    let result = match notify_socket.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(message.as_bytes(), &addr))
+        }
+        None => socket.send_to(message.as_bytes(), notify_socket),
+    };
+
+    if let Err(why) = result {
+        warn!("Unable to notify systemd: {}", why);
+    }
+}
+
+// Implements the systemd notify protocol when running as a Type=notify service: READY=1 once the
+// bouncer is ready, then WATCHDOG=1 pings while it stays ready if WatchdogSec= is set
+pub async fn systemd_watchdog(state: &BridgeState) {
+    let notify_socket = match env::var("NOTIFY_SOCKET") {
+        Ok(notify_socket) => notify_socket,
+        Err(_) => return,
+    };
+
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(why) => {
+            warn!("Unable to notify systemd: {}", why);
+            return;
+        }
+    };
+
+    // systemd expects a ping at least every WATCHDOG_USEC, so ping twice as often
+    let watchdog = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .map(|usec| Duration::from_micros(usec / 2));
+
+    let mut ticker = interval(watchdog.unwrap_or(Duration::from_secs(1)));
+    let mut notified_ready = false;
+
+    loop {
+        ticker.tick().await;
+        let (ready, _) = readiness(state).await;
+
+        if ready && !notified_ready {
+            info!("Ready, notifying systemd");
+            sd_notify(&socket, &notify_socket, "READY=1");
+            notified_ready = true;
+        }
+
+        match watchdog {
+            Some(_) if ready => sd_notify(&socket, &notify_socket, "WATCHDOG=1"),
+            Some(_) => {}
+            None if notified_ready => return,
+            None => {}
+        }
+    }
+}
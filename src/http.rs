@@ -0,0 +1,76 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::avatar;
+use crate::discord::BridgeState;
+use crate::health;
+use crate::metrics::METRICS;
+
+async fn handle(state: Arc<BridgeState>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(METRICS.render())),
+        // Liveness only needs the process to be responsive
+        (&Method::GET, "/healthz") => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from("{\"status\":\"ok\"}")),
+        (&Method::GET, "/readyz") => {
+            let (ready, breakdown) = health::readiness(&state).await;
+            Response::builder()
+                .status(match ready {
+                    true => StatusCode::OK,
+                    false => StatusCode::SERVICE_UNAVAILABLE,
+                })
+                .header("Content-Type", "application/json")
+                .body(Body::from(breakdown.to_string()))
+        }
+        // Identicons never change, so they can be cached forever
+        (&Method::GET, path) if path.starts_with("/avatars/") => match path
+            .strip_prefix("/avatars/")
+            .and_then(|name| name.strip_suffix(".png"))
+            .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+        {
+            Some(hash) => Response::builder()
+                .header("Content-Type", "image/png")
+                .header("Cache-Control", "public, max-age=31536000, immutable")
+                .body(Body::from(avatar::identicon(hash))),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not found\n")),
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found\n")),
+    };
+
+    Ok(response.unwrap())
+}
+
+pub async fn serve(addr: SocketAddr, state: Arc<BridgeState>) {
+    let server = match Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(why) => {
+            error!("Unable to listen on {}: {}", addr, why);
+            return;
+        }
+    };
+
+    info!(
+        "Serving /metrics, /healthz, /readyz and /avatars on http://{}",
+        addr
+    );
+
+    let service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+
+    if let Err(why) = server.serve(service).await {
+        error!("HTTP server failed: {}", why);
+    }
+}
@@ -1,41 +1,811 @@
+use chrono::{DateTime, Utc};
 use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::SystemTime;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
 
 use native_tls::TlsConnector;
 
+use crate::config::{EditStyle, IRCServerConfig};
+use crate::logging;
 use crate::message;
+use crate::metrics::{NetworkMetrics, METRICS};
+use crate::storage::{PlaybackCursor, Storage};
+use crate::textlog::{LogEvent, TextLog};
 use base64::encode;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 pub struct IRCSocket<T: AsyncRead + AsyncWrite + std::marker::Unpin> {
     addr: String,
-    tls: bool,
     stream: BufReader<T>,
     tx: Sender<message::BouncerMessage>,
     nick: String,
     password: String,
+    metrics: Arc<NetworkMetrics>,
+    log: Option<TextLog>,
+    // Channel members by lowercase channel and nick, with the status symbols each holds (e.g. @+)
+    members: HashMap<String, HashMap<String, String>>,
+    // Status modes and their symbols, from PREFIX
+    prefixes: Vec<(char, char)>,
+    // Channel mode groups from CHANMODES, which say which modes take a parameter
+    chanmodes: Vec<String>,
+    // Joined once registration completes
+    channels: Vec<String>,
+    // Offered by the server while negotiating, and the ones it acknowledged
+    available_caps: Vec<String>,
+    caps: HashSet<String>,
+    // Where history playback resumes for each (lowercased) target
+    cursors: HashMap<String, PlaybackCursor>,
+    // Open chathistory batches by reference tag
+    batches: HashMap<String, Playback>,
+    // Messages played back per (lowercased) target on this connection
+    played: HashMap<String, usize>,
+    history_limit: usize,
+    storage: Arc<Storage>,
+    edit_style: EditStyle,
+    reaction_fallback: String,
+    // Edits waiting out the cooldown, and when each message was last edited, by Discord message id
+    pending_edits: HashMap<u64, PendingEdit>,
+    last_edit: HashMap<u64, Instant>,
+    // Targets the owner is typing to, by name
+    typing: HashMap<String, Typing>,
+    away: Option<String>,
+    // Topics sent on joining, waiting to hear who set them
+    topics: HashMap<String, String>,
+    registered: bool,
+    // The Discord user this connection speaks for, None for the main connection
+    puppet: Option<u64>,
+    puppet_nicks: PuppetNicks,
+    rx: Option<Receiver<message::BouncerMessage>>,
+    // Set once we sent QUIT, after which the server closing the connection is expected
+    quitting: bool,
+}
+
+// Nicks of the connected puppets on each network, so what they say isn't relayed back to Discord
+#[derive(Clone, Default)]
+pub struct PuppetNicks(Arc<Mutex<HashSet<(String, String)>>>);
+
+impl PuppetNicks {
+    fn insert(&self, network: &str, nick: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert((String::from(network), nick.to_lowercase()));
+    }
+
+    fn remove(&self, network: &str, nick: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&(String::from(network), nick.to_lowercase()));
+    }
+
+    fn contains(&self, network: &str, nick: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .contains(&(String::from(network), nick.to_lowercase()))
+    }
+}
+
+// Capabilities we make use of when the server offers them
+const WANTED_CAPS: [&str; 8] = [
+    "message-tags",
+    "server-time",
+    "batch",
+    "draft/chathistory",
+    "echo-message",
+    "labeled-response",
+    "draft/message-redaction",
+    "account-tag",
+];
+
+const DEFAULT_REACTION_FALLBACK: &str = "reacted {emoji} to {nick}'s message";
+
+// Edits to the same message closer together than this are held back, and only the latest one is sent
+const EDIT_COOLDOWN: Duration = Duration::from_secs(10);
+
+// Discord only says when someone starts typing, and shows it for 10 seconds
+const DISCORD_TYPING_DURATION: Duration = Duration::from_secs(10);
+// Receivers drop an active notification after 6 seconds, and a paused one after 30
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+const TYPING_PAUSED_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Typing {
+    until: Instant,
+    due: Instant,
+    paused: bool,
+}
+
+struct PendingEdit {
+    target: String,
+    content: String,
+    due: Instant,
+}
+
+// IRC messages are a single line, so Discord's line breaks become spaces. A stray carriage return
+// would otherwise make send_raw refuse the line and drop the connection
+fn single_line(content: &str) -> String {
+    content
+        .replace("\r\n", "\n")
+        .split(['\r', '\n'])
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Where a part of a long message starting at start ends, at most max_len bytes in and without
+// cutting a character in half
+fn part_end(text: &str, start: usize, max_len: usize) -> usize {
+    let mut end = min(start + max_len, text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    // A character longer than the whole part is sent over the limit rather than not at all
+    if end == start {
+        end = text[start..]
+            .chars()
+            .next()
+            .map_or(start, |c| start + c.len_utf8());
+    }
+    end
+}
+
+// Falls back to a correction line when the change can't be written as a whole-word substitution
+fn correction_line(style: EditStyle, old: &str, new: &str) -> String {
+    if style == EditStyle::Sed {
+        let old: Vec<char> = old.chars().collect();
+        let new: Vec<char> = new.chars().collect();
+
+        let mut prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let mut suffix = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(old.len() - prefix)
+            .min(new.len() - prefix);
+
+        // Widen the change to whole words so the pattern is less likely to match somewhere else
+        while prefix > 0 && !old[prefix - 1].is_whitespace() {
+            prefix -= 1;
+        }
+        while suffix > 0 && !old[old.len() - suffix].is_whitespace() {
+            suffix -= 1;
+        }
+
+        let removed: String = old[prefix..old.len() - suffix].iter().collect();
+        let added: String = new[prefix..new.len() - suffix].iter().collect();
+
+        if !removed.trim().is_empty() && !removed.contains('/') && !added.contains('/') {
+            return format!("s/{}/{}/", removed, added);
+        }
+    }
+
+    format!("* correction: {}", new)
+}
+
+// Reconnecting backs off from the first delay up to the longest, and starts over once registered
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+// Messages requested per CHATHISTORY page, lowered if the server advertises a smaller limit
+const HISTORY_PAGE_SIZE: usize = 100;
+// Longer gaps are cut short rather than flooding Discord (and the message bus) with history
+const MAX_PLAYBACK_PER_TARGET: usize = 500;
+
+// A chathistory batch being received
+struct Playback {
+    target: String,
+    count: usize,
+    // Where the page started, to notice when it didn't get us any further
+    after: Option<String>,
+}
+
+// Why a line can't be sent as is. Tags have a separate limit, and a stray line break would let the
+// rest be sent as a command of its own
+fn invalid_line(line: &str) -> Option<String> {
+    if line.contains(['\r', '\n']) {
+        return Some(String::from("it contains a line break"));
+    }
+    let length = parse_tags(line).1.len() + 2;
+    if length > 512 {
+        return Some(format!("it is {} bytes long, more than 512", length));
+    }
+    None
+}
+
+fn is_quit(line: &str) -> bool {
+    line.split(' ')
+        .next()
+        .is_some_and(|command| command.eq_ignore_ascii_case("QUIT"))
+}
+
+// Splits off the IRCv3 message tags, if any: "@a=b;c :prefix CMD ..." -> ({a: b, c: ""}, ":prefix CMD ...")
+fn parse_tags(line: &str) -> (HashMap<String, String>, &str) {
+    match line.strip_prefix('@').and_then(|line| line.split_once(' ')) {
+        Some((tags, rest)) => (
+            tags.split(';')
+                .map(|tag| match tag.split_once('=') {
+                    Some((key, value)) => (String::from(key), unescape_tag(value)),
+                    None => (String::from(tag), String::new()),
+                })
+                .collect(),
+            rest,
+        ),
+        None => (HashMap::new(), line),
+    }
+}
+
+// Reply tags count against the line length we split messages at, so absurdly long msgids fall back to the nick
+const MAX_REPLY_MSGID_LENGTH: usize = 100;
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\:")
+        .replace(' ', "\\s")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+fn unescape_tag(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// ":nick!user@host" -> ("nick", "user@host")
+fn split_prefix(prefix: &str) -> (&str, &str) {
+    let prefix = prefix.trim_start_matches(':');
+    prefix.split_once('!').unwrap_or((prefix, ""))
+}
+
+// Joins the remaining parameters, dropping the ':' that marks the trailing one
+fn trailing<'a>(params: impl Iterator<Item = &'a str>) -> String {
+    let joined = params.collect::<Vec<&str>>().join(" ");
+    String::from(joined.strip_prefix(':').unwrap_or(&joined))
 }
 
 async fn process_outgoing_messages(
     rx: &mut Receiver<message::BouncerMessage>,
     addr: &str,
+    puppet: Option<u64>,
 ) -> Option<message::BouncerMessage> {
     if let Ok(cmd) = rx.recv().await {
-        if cmd.state == message::MessageState::OUTGOING && cmd.network == addr {
+        let outgoing = matches!(
+            cmd.state,
+            message::MessageState::OUTGOING
+                | message::MessageState::COMMAND
+                | message::MessageState::EDIT
+                | message::MessageState::DELETE
+                | message::MessageState::REACT
+                | message::MessageState::TYPING
+                | message::MessageState::AWAY
+        );
+        if outgoing && cmd.network == addr && cmd.puppet == puppet {
             return Some(cmd);
         }
     }
-    return None;
+    None
 }
 
 impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> IRCSocket<T> {
     async fn send_raw(&mut self, irc_message: &str) -> Result<(), Box<dyn std::error::Error>> {
-        assert!(irc_message.as_bytes().len() <= 512);
+        let lines = irc_message.strip_suffix("\r\n").unwrap_or(irc_message);
+        if let Some(why) = lines.split("\r\n").find_map(invalid_line) {
+            bail!("Refusing to send a line, {}", why);
+        }
+
+        for line in irc_message.lines() {
+            trace!(target: logging::RAW_IRC, "> {}", logging::redact_raw(line));
+            self.metrics.lines_out.fetch_add(1, Ordering::Relaxed);
+        }
         self.stream.write_all(irc_message.as_bytes()).await?;
         Ok(())
     }
 
+    // Puppets only speak, everything they see is relayed by the main connection
+    fn relay(&self, message: message::BouncerMessage) -> Result<(), Box<dyn std::error::Error>> {
+        if self.puppet.is_none() {
+            self.tx.send(message)?;
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, target: &str, time: DateTime<Utc>, event: &LogEvent) {
+        if let Some(log) = &mut self.log {
+            if let Err(why) = log.write(target, time, event) {
+                warn!("Unable to write to the {} log: {}", target, why);
+            }
+        }
+    }
+
+    fn log_message(
+        &mut self,
+        command: &str,
+        target: &str,
+        time: DateTime<Utc>,
+        nick: &str,
+        content: &str,
+    ) {
+        let event = match (command, content.strip_prefix("\x01ACTION ")) {
+            ("PRIVMSG", Some(action)) => LogEvent::Action {
+                nick,
+                content: action.trim_end_matches('\x01'),
+            },
+            ("PRIVMSG", None) => LogEvent::Message { nick, content },
+            _ => LogEvent::Notice { nick, content },
+        };
+        self.log(target, time, &event);
+    }
+
+    // Requests the capabilities we want out of the ones the server offered, or finishes negotiating
+    async fn request_caps(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut wanted: Vec<&str> = WANTED_CAPS.to_vec();
+        if !self.password.is_empty() {
+            wanted.push("sasl");
+        }
+        wanted.retain(|cap| self.available_caps.iter().any(|c| c == cap));
+
+        if !self.password.is_empty() && !wanted.contains(&"sasl") {
+            warn!("The server does not support SASL, connecting without authenticating");
+        }
+
+        match wanted.is_empty() {
+            true => self.send_raw("CAP END\r\n").await,
+            false => {
+                self.send_raw(&format!("CAP REQ :{}\r\n", wanted.join(" ")))
+                    .await
+            }
+        }
+    }
+
+    // IRC messages can only have a maximum length of 512 bytes per RFC, so long ones are split.
+    // Each part is labelled with the Discord message it came from, so its msgid can be recorded
+    // when the server echoes it back.
+    async fn send_privmsg(
+        &mut self,
+        target: &str,
+        text: &str,
+        mut reply_tag: Option<String>,
+        discord_message_id: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let labelled = self.caps.contains("labeled-response") && self.caps.contains("echo-message");
+        let prefix = format!("PRIVMSG {} :", target);
+        let prefix_len = prefix.len();
+        let mut curr_len = 0;
+        let mut part = 0;
+
+        // Naive split (e.g. 'A"*512-prefix+'bcd' = all As, then bcd)
+        while curr_len < text.len() {
+            let mut tags = Vec::new();
+            // Only the first part is the reply
+            if let Some(reply_tag) = reply_tag.take() {
+                tags.push(reply_tag);
+            }
+            if let (true, Some(id)) = (labelled, discord_message_id) {
+                tags.push(format!("label={}-{}", id, part));
+            }
+            let tags = match tags.is_empty() {
+                true => String::new(),
+                false => format!("@{} ", tags.join(";")),
+            };
+
+            // Prepare the next part of the message to be sent
+            // 512 (max amount) - 2 (\r\n) - prefix_len (PRIVMSG CHANNEL)
+            // Note: For some reason, 446 appears to be the max we can send on libera chat
+            let end = part_end(text, curr_len, 446 - 2 - prefix_len);
+            let buffer_to_send = format!("{}{}{}\r\n", tags, prefix, &text[curr_len..end]);
+            curr_len = end;
+            part += 1;
+            self.send_raw(&buffer_to_send).await?;
+        }
+        Ok(())
+    }
+
+    fn queue_edit(&mut self, discord_message_id: u64, target: String, content: String) {
+        let now = Instant::now();
+        self.last_edit
+            .retain(|_, edited| now.duration_since(*edited) < EDIT_COOLDOWN);
+
+        // A newer edit replaces one that is still waiting, keeping its place
+        let due = match self.pending_edits.get(&discord_message_id) {
+            Some(pending) => pending.due,
+            None => self
+                .last_edit
+                .get(&discord_message_id)
+                .map_or(now, |edited| *edited + EDIT_COOLDOWN),
+        };
+
+        self.pending_edits.insert(
+            discord_message_id,
+            PendingEdit {
+                target,
+                content,
+                due,
+            },
+        );
+    }
+
+    async fn send_due_edits(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let now = Instant::now();
+        let due: Vec<u64> = self
+            .pending_edits
+            .iter()
+            .filter(|(_, edit)| edit.due <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in due {
+            let edit = self.pending_edits.remove(&id).unwrap();
+            self.last_edit.insert(id, now);
+            self.send_edit(id, edit).await?;
+        }
+        Ok(())
+    }
+
+    // Redacts the original and sends the edited message where the network supports it,
+    // and sends a correction otherwise
+    async fn send_edit(
+        &mut self,
+        discord_message_id: u64,
+        edit: PendingEdit,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let original = match self
+            .storage
+            .blocking(move |storage| storage.find_by_discord_id(discord_message_id))
+            .await
+        {
+            Ok(Some(original)) => original,
+            // Not a message that was relayed to IRC
+            Ok(None) => return Ok(()),
+            Err(why) => {
+                error!("Unable to look up edited message: {}", why);
+                return Ok(());
+            }
+        };
+
+        // Discord also reports embeds being added to a message as an edit. Messages are recorded
+        // as they were written on Discord, and edits as they are sent to IRC
+        let original_content = single_line(&original.content);
+        if original_content == edit.content {
+            return Ok(());
+        }
+
+        let addr = self.addr.clone();
+        let parts = self
+            .storage
+            .blocking(move |storage| storage.irc_parts(&addr, discord_message_id))
+            .await
+            .unwrap_or_else(|why| {
+                error!(
+                    "Unable to look up the IRC lines of an edited message: {}",
+                    why
+                );
+                Vec::new()
+            });
+
+        let line = match self.caps.contains("draft/message-redaction") && !parts.is_empty() {
+            true => {
+                for msgid in &parts {
+                    self.send_raw(&format!("REDACT {} {} :Edited\r\n", edit.target, msgid))
+                        .await?;
+                }
+                let addr = self.addr.clone();
+                if let Err(why) = self
+                    .storage
+                    .blocking(move |storage| storage.remove_irc_parts(&addr, discord_message_id))
+                    .await
+                {
+                    error!("Unable to forget redacted lines: {}", why);
+                }
+                edit.content.clone()
+            }
+            false => correction_line(self.edit_style, &original_content, &edit.content),
+        };
+
+        let nick = self.nick.clone();
+        self.log_message("PRIVMSG", &edit.target, Utc::now(), &nick, &line);
+        self.send_privmsg(&edit.target, &line, None, Some(discord_message_id))
+            .await?;
+
+        let content = edit.content.clone();
+        if let Err(why) = self
+            .storage
+            .blocking(move |storage| storage.update_content(discord_message_id, &content))
+            .await
+        {
+            error!("Unable to record edited message: {}", why);
+        }
+        Ok(())
+    }
+
+    // Reactions are tagged onto the message where the network supports it, and described otherwise
+    async fn send_reaction(
+        &mut self,
+        target: &str,
+        emoji: &str,
+        reacted: &message::Reply,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match &reacted.msgid {
+            Some(msgid)
+                if self.caps.contains("message-tags") && msgid.len() <= MAX_REPLY_MSGID_LENGTH =>
+            {
+                self.send_raw(&format!(
+                    "@+draft/react={};+draft/reply={} TAGMSG {}\r\n",
+                    escape_tag(emoji),
+                    escape_tag(msgid),
+                    target
+                ))
+                .await
+            }
+            _ => {
+                if self.reaction_fallback.is_empty() {
+                    return Ok(());
+                }
+
+                let action = format!(
+                    "\x01ACTION {}\x01",
+                    self.reaction_fallback
+                        .replace("{emoji}", emoji)
+                        .replace("{nick}", reacted.nick.as_deref().unwrap_or("someone"))
+                );
+                let nick = self.nick.clone();
+                self.log_message("PRIVMSG", target, Utc::now(), &nick, &action);
+                self.send_privmsg(target, &action, None, None).await
+            }
+        }
+    }
+
+    fn send_topic(
+        &self,
+        channel: String,
+        nick: String,
+        topic: String,
+        time: DateTime<Utc>,
+        announce: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.relay(message::BouncerMessage {
+            channel,
+            network: String::from(&self.addr),
+            user: nick,
+            content: topic,
+            time: Some(SystemTime::from(time)),
+            playback: !announce,
+            ..message::BouncerMessage::new(message::MessageState::TOPIC)
+        })?;
+        Ok(())
+    }
+
+    async fn send_away(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let away = match &self.away {
+            Some(away) => format!("AWAY :{}\r\n", away),
+            None => String::from("AWAY\r\n"),
+        };
+        self.send_raw(&away).await
+    }
+
+    async fn send_typing(
+        &mut self,
+        target: &str,
+        status: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_raw(&format!("@+typing={} TAGMSG {}\r\n", status, target))
+            .await
+    }
+
+    async fn start_typing(&mut self, target: String) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.caps.contains("message-tags") {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let resumed = match self.typing.get_mut(&target) {
+            Some(typing) => {
+                typing.until = now + DISCORD_TYPING_DURATION;
+                std::mem::replace(&mut typing.paused, false)
+            }
+            None => true,
+        };
+
+        // Still active ones are refreshed on their own schedule
+        if resumed {
+            self.send_typing(&target, "active").await?;
+            self.typing.insert(
+                target,
+                Typing {
+                    until: now + DISCORD_TYPING_DURATION,
+                    due: now + TYPING_REFRESH,
+                    paused: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // Keeps active notifications from expiring while Discord shows the owner typing, then lets them
+    // lapse to paused and done
+    async fn send_due_typing(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .typing
+            .iter()
+            .filter(|(_, typing)| typing.due <= now)
+            .map(|(target, _)| target.clone())
+            .collect();
+
+        for target in due {
+            let typing = self.typing.get_mut(&target).unwrap();
+
+            let status = if typing.paused {
+                self.typing.remove(&target);
+                "done"
+            } else if now < typing.until {
+                typing.due = now + TYPING_REFRESH;
+                "active"
+            } else {
+                typing.paused = true;
+                typing.due = now + TYPING_PAUSED_TIMEOUT;
+                "paused"
+            };
+            self.send_typing(&target, status).await?;
+        }
+        Ok(())
+    }
+
+    // Messages can only be taken back where the network supports redaction
+    async fn send_delete(
+        &mut self,
+        discord_message_id: u64,
+        target: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.pending_edits.remove(&discord_message_id);
+
+        let addr = self.addr.clone();
+        let parts = match self
+            .storage
+            .blocking(move |storage| storage.irc_parts(&addr, discord_message_id))
+            .await
+        {
+            Ok(parts) => parts,
+            Err(why) => {
+                error!(
+                    "Unable to look up the IRC lines of a deleted message: {}",
+                    why
+                );
+                return Ok(());
+            }
+        };
+
+        // Not relayed, or sent before the msgids of our own lines were known
+        if parts.is_empty() {
+            return Ok(());
+        }
+
+        if !self.caps.contains("draft/message-redaction") {
+            debug!("Unable to delete message on a network without redaction");
+            return Ok(());
+        }
+
+        for msgid in &parts {
+            self.send_raw(&format!("REDACT {} {} :Deleted\r\n", target, msgid))
+                .await?;
+        }
+        let addr = self.addr.clone();
+        if let Err(why) = self
+            .storage
+            .blocking(move |storage| storage.remove_sent(&addr, discord_message_id))
+            .await
+        {
+            error!("Unable to forget deleted message: {}", why);
+        }
+        Ok(())
+    }
+
+    // The CHATHISTORY reference to the last message seen from a target
+    fn history_reference(&self, target: &str) -> Option<String> {
+        match self.cursors.get(&target.to_lowercase())? {
+            PlaybackCursor {
+                msgid: Some(msgid), ..
+            } => Some(format!("msgid={}", msgid)),
+            cursor => Some(format!(
+                "timestamp={}",
+                DateTime::<Utc>::from(cursor.timestamp).format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            )),
+        }
+    }
+
+    // Asks for everything sent to a target since the last message we relayed from it
+    async fn request_history(&mut self, target: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.caps.contains("draft/chathistory") {
+            return Ok(());
+        }
+
+        // Nothing was ever relayed from here, so there is nothing to catch up on
+        let reference = match self.history_reference(target) {
+            Some(reference) => reference,
+            None => return Ok(()),
+        };
+
+        self.send_raw(&format!(
+            "CHATHISTORY AFTER {} {} {}\r\n",
+            target, reference, self.history_limit
+        ))
+        .await
+    }
+
+    // Removes a nick from a channel, or forgets the channel when we are the one leaving it
+    fn part(&mut self, channel: &str, nick: &str) {
+        if nick.eq_ignore_ascii_case(&self.nick) {
+            self.members.remove(&channel.to_lowercase());
+        } else if let Some(members) = self.members.get_mut(&channel.to_lowercase()) {
+            members.remove(&nick.to_lowercase());
+        }
+    }
+
+    // Channels we share with a nick, optionally renaming it in each of them
+    fn shared_channels(&mut self, nick: &str, rename: Option<&str>) -> Vec<String> {
+        let nick = nick.to_lowercase();
+        let mut channels = Vec::new();
+
+        for (channel, members) in self.members.iter_mut() {
+            if let Some(status) = members.remove(&nick) {
+                if let Some(new) = rename {
+                    members.insert(new.to_lowercase(), status);
+                }
+                channels.push(String::from(channel));
+            }
+        }
+        channels
+    }
+
+    // The highest status a nick holds in a channel
+    fn status(&self, channel: &str, nick: &str) -> Option<char> {
+        let status = self
+            .members
+            .get(&channel.to_lowercase())?
+            .get(&nick.to_lowercase())?;
+
+        self.prefixes
+            .iter()
+            .map(|(_, symbol)| *symbol)
+            .find(|symbol| status.contains(*symbol))
+    }
+
+    // Lists (type A) and keys (type B) always take a parameter, limits (type C) only when set
+    fn mode_takes_param(&self, mode: char, adding: bool) -> bool {
+        match self.chanmodes.iter().position(|group| group.contains(mode)) {
+            Some(0) | Some(1) => true,
+            Some(2) => adding,
+            _ => false,
+        }
+    }
+
     async fn receive_incoming_data(&mut self, line: &mut String) -> Result<usize, String> {
         // Read from the underlying stream and propogate any errors up
         match self.stream.read_line(line).await {
@@ -44,6 +814,8 @@ impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> IRCSocket<T> {
                 if size > 1 {
                     line.truncate(line.len() - 2);
                 }
+                trace!(target: logging::RAW_IRC, "< {}", line);
+                self.metrics.lines_in.fetch_add(1, Ordering::Relaxed);
                 Ok(size)
             }
             Err(e) => Err(format!("{}", e)),
@@ -52,7 +824,10 @@ impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> IRCSocket<T> {
 
     pub async fn do_main_loop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let mut line = String::new();
-        let mut rx = self.tx.subscribe();
+        let mut rx = match self.rx.take() {
+            Some(rx) => rx,
+            None => self.tx.subscribe(),
+        };
         let addr = String::from(&self.addr);
 
         let get_username_from_blob = |blob: &str| -> Result<String, Box<dyn std::error::Error>> {
@@ -65,45 +840,135 @@ impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> IRCSocket<T> {
             };
 
             chars.next();
-            return Ok(String::from(chars.as_str()));
+            Ok(String::from(chars.as_str()))
         };
 
         loop {
+            self.metrics
+                .send_queue
+                .store(rx.len() as u64, Ordering::Relaxed);
+
+            let next_due = self
+                .pending_edits
+                .values()
+                .map(|edit| edit.due)
+                .chain(self.typing.values().map(|typing| typing.due))
+                .min();
+
             tokio::select! {
-                x = process_outgoing_messages(&mut rx, &addr) => {
+                // Held back until registered, since servers reject them before then
+                x = process_outgoing_messages(&mut rx, &addr, self.puppet), if self.registered => {
                     if let Some(cmd) = x {
-                        // IRC messages can only have a maximum length of 512 bytes per RFC
-                        // If messages exceed this, we need to split them.
-                        let text_to_process = cmd.content.split("\n").collect::<Vec<&str>>().join(" ");
-                        let prefix = format!("PRIVMSG {} :", cmd.channel);
-                        let prefix_len = prefix.len();
-                        let mut curr_len = 0;
-
-                        // Naive split (e.g. 'A"*512-prefix+'bcd' = all As, then bcd)
-                        while curr_len < text_to_process.len() {
-                            // Prepare the next part of the message to be sent
-                            // 512 (max amount) - 2 (\r\n) - prefix_len (PRIVMSG CHANNEL)
-                            // Note: For some reason, 446 appears to be the max we can send on libera chat
-                            let offset = 446 - 2 - prefix_len;
-                            let buffer_to_send = format!("{}{}\r\n", prefix, &text_to_process[curr_len..min(curr_len+offset, text_to_process.len())]);
-                            curr_len += offset;
-                            self.send_raw(&buffer_to_send).await?;
+                        if cmd.state == message::MessageState::COMMAND {
+                            // These come from Discord users, so a bad one shouldn't end the connection
+                            match invalid_line(&cmd.content) {
+                                Some(why) => warn!("Not sending {}: {}", logging::redact_raw(&cmd.content), why),
+                                None => {
+                                    self.quitting |= is_quit(&cmd.content);
+                                    self.send_raw(&format!("{}\r\n", cmd.content)).await?;
+                                }
+                            }
+                            continue;
+                        }
+
+                        if cmd.state == message::MessageState::AWAY {
+                            self.away = Some(single_line(&cmd.content)).filter(|away| !away.is_empty());
+
+                            // Otherwise it is set once registered
+                            if self.registered {
+                                self.send_away().await?;
+                            }
+                            continue;
+                        }
+
+                        if cmd.state == message::MessageState::TYPING {
+                            self.start_typing(cmd.channel).await?;
+                            continue;
+                        }
+
+                        if cmd.state == message::MessageState::REACT {
+                            if let Some(reacted) = &cmd.reply_to {
+                                self.send_reaction(&cmd.channel, &cmd.content, reacted).await?;
+                            }
+                            continue;
                         }
+
+                        if cmd.state == message::MessageState::DELETE {
+                            if let Some(id) = cmd.discord_message_id {
+                                self.send_delete(id, &cmd.channel).await?;
+                            }
+                            continue;
+                        }
+
+                        let mut text_to_process = single_line(&cmd.content);
+
+                        if cmd.state == message::MessageState::EDIT {
+                            if let Some(id) = cmd.discord_message_id {
+                                self.queue_edit(id, cmd.channel, text_to_process);
+                            }
+                            continue;
+                        }
+
+                        // Replies are tagged where the network supports it, and addressed to the nick otherwise
+                        let mut reply_tag = None;
+                        if let Some(reply) = &cmd.reply_to {
+                            match &reply.msgid {
+                                Some(msgid) if self.caps.contains("message-tags") && msgid.len() <= MAX_REPLY_MSGID_LENGTH => {
+                                    reply_tag = Some(format!("+draft/reply={}", escape_tag(msgid)));
+                                }
+                                _ => if let Some(nick) = &reply.nick {
+                                    text_to_process = format!("{}: {}", nick, text_to_process);
+                                },
+                            }
+                        }
+
+                        // Sending the message ends the typing notification
+                        self.typing.remove(&cmd.channel);
+                        let nick = self.nick.clone();
+                        self.log_message("PRIVMSG", &cmd.channel, Utc::now(), &nick, &text_to_process);
+                        self.send_privmsg(&cmd.channel, &text_to_process, reply_tag, cmd.discord_message_id).await?;
                     }
                 },
+                _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                    self.send_due_edits().await?;
+                    self.send_due_typing().await?;
+                },
                 x = self.receive_incoming_data(&mut line) => {
+                    // The connection ending is only expected after we sent QUIT, otherwise it is reconnected
+                    if self.quitting && matches!(x, Ok(0) | Err(_)) {
+                        return Ok(());
+                    }
                     if x.is_err() {
                         bail!(x.err().unwrap())
                     }
+                    if let Ok(0) = x {
+                        bail!("Connection closed by the server");
+                    }
 
+                    // read_line appends, so the buffer is emptied before anything else can go wrong
+                    let line = std::mem::take(&mut line);
+                    let (tags, untagged) = parse_tags(&line);
+                    let server_time = tags
+                        .get("time")
+                        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                        .map(|time| time.with_timezone(&Utc));
+                    let time = server_time.unwrap_or_else(Utc::now);
 
-                    let mut split = line.split(" ");
+                    let mut split = untagged.split(" ");
                     let split_first = match split.next() {
                         Some(split_f) => split_f,
                         None => bail!(format!("do_main_loop: Received unexpected empty string.")),
                     };
 
                     match split_first {
+                        // Sent without a prefix while authenticating, asking for our credentials
+                        "AUTHENTICATE" => {
+                            let sasl_plain = encode(format!(
+                                "{}\x00{}\x00{}",
+                                self.nick, self.nick, self.password
+                            ));
+                            self.send_raw(&format!("AUTHENTICATE {}\r\n", sasl_plain)).await?;
+                        }
                         "PING" => {
                             match split.next() {
                                 Some(pong) => self.send_raw(&format!("PONG {}\r\n", pong)).await?,
@@ -124,114 +989,781 @@ impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> IRCSocket<T> {
                                     let channel = split.next().unwrap().to_string();
                                     let content =  String::from(&split.collect::<Vec<&str>>().join(" ").as_str()[1..]);
                                     let ping = content.contains(&self.nick);
-                                    self.tx.send(message::BouncerMessage{
-                                        channel,
-                                        network: String::from(&self.addr),
-                                        state: message::MessageState::INCOMING,
-                                        user: get_username_from_blob(split_first)?,
-                                        content,
-                                        ping
-                                    })?;
+                                    let user = get_username_from_blob(split_first)?;
+                                    let msgid = tags.get("msgid").cloned();
+
+                                    let playback = match tags.get("batch").and_then(|id| self.batches.get_mut(id)) {
+                                        Some(batch) => {
+                                            batch.count += 1;
+                                            true
+                                        }
+                                        None => false,
+                                    };
+
+                                    // Private messages (and server notices) are logged under whoever sent them
+                                    let is_channel = channel.starts_with(['#', '&', '+', '!']);
+                                    let target = match is_channel {
+                                        true => &channel,
+                                        false => &user,
+                                    };
+
+                                    if is_channel && (msgid.is_some() || server_time.is_some()) {
+                                        self.cursors.insert(channel.to_lowercase(), PlaybackCursor {
+                                            msgid: msgid.clone(),
+                                            timestamp: SystemTime::from(time),
+                                        });
+                                    }
+
+                                    let own = user.eq_ignore_ascii_case(&self.nick);
+
+                                    // The server echoing what we sent, labelled with the Discord message it came from
+                                    if own && !playback && self.caps.contains("echo-message") {
+                                        let discord_message_id = tags
+                                            .get("label")
+                                            .and_then(|label| label.split('-').next())
+                                            .and_then(|id| id.parse::<u64>().ok());
+
+                                        if let (Some(id), Some(msgid)) = (discord_message_id, &msgid) {
+                                            let (addr, msgid) = (self.addr.clone(), msgid.clone());
+                                            if let Err(why) = self.storage.blocking(move |storage| storage.add_irc_part(&addr, id, &msgid)).await {
+                                                error!("Unable to record the msgid of a sent message: {}", why);
+                                            }
+                                        }
+                                    } else if self.puppet_nicks.contains(&self.addr, &user) {
+                                        // Puppets speak for people in Discord, who have already seen it there
+                                        if !playback {
+                                            self.log_message(next_split, target, time, &user, &content);
+                                        }
+                                    } else if !(playback && own) {
+                                        // What we sent ourselves came from Discord in the first place, and was logged then
+                                        self.log_message(next_split, target, time, &user, &content);
+                                        let prefix = match is_channel {
+                                            true => self.status(&channel, &user),
+                                            false => None,
+                                        };
+
+                                        self.relay(message::BouncerMessage {
+                                            channel,
+                                            network: String::from(&self.addr),
+                                            user,
+                                            content,
+                                            ping,
+                                            msgid,
+                                            time: Some(SystemTime::from(time)),
+                                            playback,
+                                            reply_to: tags.get("+draft/reply").map(|msgid| message::Reply {
+                                                msgid: Some(msgid.clone()),
+                                                nick: None,
+                                            }),
+                                            account: tags.get("account").cloned(),
+                                            prefix,
+                                            ..message::BouncerMessage::new(message::MessageState::INCOMING)
+                                        })?;
+                                    }
+                                }
+                                "JOIN" => {
+                                    let (nick, host) = split_prefix(split_first);
+                                    let channel = split.next().unwrap_or("").trim_start_matches(':');
+                                    self.members.entry(channel.to_lowercase()).or_default().insert(nick.to_lowercase(), String::new());
+
+                                    // Rejoined when reconnecting
+                                    if nick.eq_ignore_ascii_case(&self.nick) && !self.channels.iter().any(|c| c.eq_ignore_ascii_case(channel)) {
+                                        self.channels.push(String::from(channel));
+                                    }
+                                    self.log(channel, time, &LogEvent::Join { nick, host });
+
+                                    // Catch up on anything missed while we were away
+                                    if nick.eq_ignore_ascii_case(&self.nick) && self.puppet.is_none() {
+                                        self.request_history(channel).await?;
+                                    }
+                                }
+                                "CAP" => {
+                                    let _target = split.next();
+                                    let subcommand = split.next().unwrap_or("");
+                                    let params: Vec<&str> = split.collect();
+
+                                    // A '*' before the list means more lines follow
+                                    let (more, caps) = match params.as_slice() {
+                                        ["*", caps @ ..] => (true, trailing(caps.iter().copied())),
+                                        caps => (false, trailing(caps.iter().copied())),
+                                    };
+
+                                    match subcommand {
+                                        "LS" => {
+                                            // Values (e.g. sasl=PLAIN,EXTERNAL) aren't needed
+                                            self.available_caps.extend(caps.split(' ').map(|cap| {
+                                                String::from(cap.split('=').next().unwrap())
+                                            }));
+                                            if !more {
+                                                self.request_caps().await?;
+                                            }
+                                        }
+                                        "ACK" => {
+                                            self.caps.extend(caps.split(' ').map(String::from));
+
+                                            // Authenticate using SASL (only PLAIN is supported for now)
+                                            // Protocol info based on https://ircv3.net/specs/extensions/sasl-3.1
+                                            match self.caps.contains("sasl") {
+                                                true => self.send_raw("AUTHENTICATE PLAIN\r\n").await?,
+                                                false => self.send_raw("CAP END\r\n").await?,
+                                            }
+                                        }
+                                        "NAK" => {
+                                            warn!("The server refused capabilities {}", caps);
+                                            self.send_raw("CAP END\r\n").await?;
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                                // RPL_SASLSUCCESS
+                                "903" => {
+                                    info!("Authenticated with SASL");
+                                    self.send_raw("CAP END\r\n").await?;
+                                }
+                                // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED, ERR_SASLALREADY
+                                "902" | "904" | "905" | "906" | "907" => {
+                                    error!("SASL authentication failed: {}", trailing(split));
+                                    self.send_raw("CAP END\r\n").await?;
+                                }
+                                // RPL_ISUPPORT, CHATHISTORY=<n> is the most messages the server returns per request
+                                "005" => {
+                                    for token in split {
+                                        if let Some(Ok(limit)) = token.strip_prefix("CHATHISTORY=").map(str::parse::<usize>) {
+                                            // 0 means no limit
+                                            if limit > 0 {
+                                                self.history_limit = limit.min(HISTORY_PAGE_SIZE);
+                                            }
+                                        } else if let Some((modes, symbols)) = token.strip_prefix("PREFIX=(").and_then(|prefix| prefix.split_once(')')) {
+                                            // e.g. PREFIX=(qaohv)~&@%+, highest first
+                                            self.prefixes = modes.chars().zip(symbols.chars()).collect();
+                                        } else if let Some(groups) = token.strip_prefix("CHANMODES=") {
+                                            self.chanmodes = groups.split(',').map(String::from).collect();
+                                        }
+                                    }
+                                }
+                                "MODE" => {
+                                    let channel = split.next().unwrap_or("").to_lowercase();
+                                    let modes = split.next().unwrap_or("").trim_start_matches(':');
+                                    let mut params = split.map(|param| param.trim_start_matches(':'));
+                                    let mut adding = true;
+
+                                    for mode in modes.chars() {
+                                        match mode {
+                                            '+' => adding = true,
+                                            '-' => adding = false,
+                                            _ => if let Some(&(_, symbol)) = self.prefixes.iter().find(|(status, _)| *status == mode) {
+                                                let nick = params.next().unwrap_or("").to_lowercase();
+                                                if let Some(status) = self.members.get_mut(&channel).and_then(|members| members.get_mut(&nick)) {
+                                                    status.retain(|held| held != symbol);
+                                                    if adding {
+                                                        status.push(symbol);
+                                                    }
+                                                }
+                                            } else if self.mode_takes_param(mode, adding) {
+                                                params.next();
+                                            },
+                                        }
+                                    }
+                                }
+                                "BATCH" => {
+                                    let reference = split.next().unwrap_or("");
+
+                                    if let Some(id) = reference.strip_prefix('+') {
+                                        if split.next() == Some("chathistory") {
+                                            let target = split.next().unwrap_or("");
+                                            self.batches.insert(String::from(id), Playback {
+                                                target: String::from(target),
+                                                count: 0,
+                                                after: self.history_reference(target),
+                                            });
+                                        }
+                                    } else if let Some(batch) = reference.strip_prefix('-').and_then(|id| self.batches.remove(id)) {
+                                        let played = self.played.entry(batch.target.to_lowercase()).or_default();
+                                        *played += batch.count;
+                                        let played = *played;
+
+                                        // A full page that moved the cursor means there is probably more
+                                        let more = batch.count >= self.history_limit
+                                            && self.history_reference(&batch.target) != batch.after;
+
+                                        if more && played < MAX_PLAYBACK_PER_TARGET {
+                                            self.request_history(&batch.target).await?;
+                                        } else if played >= MAX_PLAYBACK_PER_TARGET {
+                                            warn!("Stopped playing back {} after {} messages", batch.target, played);
+                                        } else if played > 0 {
+                                            info!("Played back {} missed messages in {}", played, batch.target);
+                                        }
+                                    }
+                                }
+                                "TAGMSG" => {
+                                    let channel = split.next().unwrap_or("").to_string();
+                                    let user = get_username_from_blob(split_first)?;
+
+                                    // Our own reactions and typing came from Discord
+                                    let own = user.eq_ignore_ascii_case(&self.nick);
+
+                                    if let (false, Some(emoji), Some(msgid)) = (own, tags.get("+draft/react"), tags.get("+draft/reply")) {
+                                        self.relay(message::BouncerMessage {
+                                            channel,
+                                            network: String::from(&self.addr),
+                                            user,
+                                            content: emoji.clone(),
+                                            msgid: tags.get("msgid").cloned(),
+                                            time: Some(SystemTime::from(time)),
+                                            reply_to: Some(message::Reply {
+                                                msgid: Some(msgid.clone()),
+                                                nick: None,
+                                            }),
+                                            ..message::BouncerMessage::new(message::MessageState::REACTION)
+                                        })?;
+                                    } else if let (false, Some(typing)) = (own, tags.get("+typing")) {
+                                        self.relay(message::BouncerMessage {
+                                            channel,
+                                            network: String::from(&self.addr),
+                                            user,
+                                            content: typing.clone(),
+                                            time: Some(SystemTime::from(time)),
+                                            ..message::BouncerMessage::new(message::MessageState::TYPINGSTATUS)
+                                        })?;
+                                    }
+                                }
+                                "REDACT" => {
+                                    let target = split.next().unwrap_or("");
+                                    let msgid = split.next().unwrap_or("");
+
+                                    if !msgid.is_empty() {
+                                        self.relay(message::BouncerMessage {
+                                            channel: String::from(target),
+                                            network: String::from(&self.addr),
+                                            user: get_username_from_blob(split_first)?,
+                                            msgid: Some(String::from(msgid)),
+                                            time: Some(SystemTime::from(time)),
+                                            ..message::BouncerMessage::new(message::MessageState::REDACT)
+                                        })?;
+                                    }
+                                }
+                                "PART" => {
+                                    let (nick, host) = split_prefix(split_first);
+                                    let channel = split.next().unwrap_or("");
+                                    let reason = trailing(split);
+                                    self.part(channel, nick);
+                                    if nick.eq_ignore_ascii_case(&self.nick) {
+                                        self.channels.retain(|c| !c.eq_ignore_ascii_case(channel));
+                                    }
+                                    self.log(channel, time, &LogEvent::Part { nick, host, reason: &reason });
+                                }
+                                "KICK" => {
+                                    let (nick, _) = split_prefix(split_first);
+                                    let channel = split.next().unwrap_or("");
+                                    let victim = split.next().unwrap_or("");
+                                    let reason = trailing(split);
+                                    self.part(channel, victim);
+                                    self.log(channel, time, &LogEvent::Kick { nick, victim, reason: &reason });
+                                }
+                                "QUIT" => {
+                                    let (nick, host) = split_prefix(split_first);
+                                    let reason = trailing(split);
+                                    for channel in self.shared_channels(nick, None) {
+                                        self.log(&channel, time, &LogEvent::Quit { nick, host, reason: &reason });
+                                    }
+                                }
+                                "NICK" => {
+                                    let (old, _) = split_prefix(split_first);
+                                    let new = trailing(split);
+
+                                    if old.eq_ignore_ascii_case(&self.nick) {
+                                        if self.puppet.is_some() {
+                                            self.puppet_nicks.remove(&self.addr, old);
+                                            self.puppet_nicks.insert(&self.addr, &new);
+                                        }
+                                        self.nick = new.clone();
+                                    }
+
+                                    for channel in self.shared_channels(old, Some(&new)) {
+                                        self.log(&channel, time, &LogEvent::Nick { old, new: &new });
+                                    }
+                                }
+                                // RPL_NAMREPLY, the members of a channel we joined
+                                "353" => {
+                                    let params: Vec<&str> = split.collect();
+                                    if let [_, _, channel, names @ ..] = params.as_slice() {
+                                        let symbols: Vec<char> = self.prefixes.iter().map(|(_, symbol)| *symbol).collect();
+                                        let members = self.members.entry(channel.to_lowercase()).or_default();
+                                        for name in names {
+                                            // Split off status prefixes (several with multi-prefix), and the hostmask sent with userhost-in-names
+                                            let name = name.trim_start_matches(':');
+                                            let nick = name.trim_start_matches(symbols.as_slice());
+                                            let status = &name[..name.len() - nick.len()];
+                                            let (nick, _) = split_prefix(nick);
+                                            if !nick.is_empty() {
+                                                members.insert(nick.to_lowercase(), String::from(status));
+                                            }
+                                        }
+                                    }
+                                }
+                                // ERR_NICKNAMEINUSE, puppets pick another nick rather than never registering
+                                "433" if self.puppet.is_some() && !self.registered => {
+                                    self.nick.push('_');
+                                    self.send_raw(&format!("NICK {}\r\n", self.nick)).await?;
+                                }
+                                // RPL_WELCOME, sent once registration has completed
+                                "001" => {
+                                    self.metrics.registered.store(true, Ordering::Relaxed);
+                                    self.registered = true;
+
+                                    // Servers may have shortened the nick we asked for
+                                    if let Some(nick) = split.next().filter(|nick| !nick.is_empty()) {
+                                        self.nick = String::from(nick);
+                                    }
+                                    info!("Registered as {}", self.nick);
+
+                                    if self.puppet.is_some() {
+                                        self.puppet_nicks.insert(&self.addr, &self.nick);
+                                    }
+
+                                    for channel in self.channels.clone() {
+                                        self.send_raw(&format!("JOIN {}\r\n", channel)).await?;
+                                    }
+
+                                    if self.away.is_some() {
+                                        self.send_away().await?;
+                                    }
                                 }
                                 "TOPIC" => {
-                                    self.tx.send(message::BouncerMessage{
-                                        channel: split.next().unwrap().to_string(),
-                                        network: String::from(&self.addr),
-                                        state: message::MessageState::INCOMING,
-                                        user: get_username_from_blob(split_first)?,
-                                        content:split.collect::<Vec<&str>>().join(" "),
-                                        ping: false
-                                    })?;
+                                    let channel = split.next().unwrap_or("").to_string();
+                                    let topic = trailing(split);
+                                    let (nick, _) = split_prefix(split_first);
+                                    self.log(&channel, time, &LogEvent::Topic { nick, topic: &topic });
+
+                                    // Our own changes came from Discord
+                                    let announce = !nick.eq_ignore_ascii_case(&self.nick);
+                                    self.send_topic(channel, String::from(nick), topic, time, announce)?;
                                 },
+                                // RPL_TOPIC, followed by RPL_TOPICWHOTIME when the server knows who set it
+                                "332" => {
+                                    let channel = split.nth(1).unwrap_or("").to_string();
+                                    self.topics.insert(channel, trailing(split));
+                                }
+                                "333" => {
+                                    let channel = split.nth(1).unwrap_or("").to_string();
+                                    let (nick, _) = split_prefix(split.next().unwrap_or(""));
+                                    let set_at = split
+                                        .next()
+                                        .and_then(|secs| secs.parse::<i64>().ok())
+                                        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                                        .unwrap_or(time);
+
+                                    if let Some(topic) = self.topics.remove(&channel) {
+                                        self.send_topic(channel, String::from(nick), topic, set_at, false)?;
+                                    }
+                                }
+                                // RPL_ENDOFNAMES, the last reply to joining
+                                "366" => {
+                                    let channel = split.nth(1).unwrap_or("").to_string();
+                                    if let Some(topic) = self.topics.remove(&channel) {
+                                        self.send_topic(channel, String::new(), topic, time, false)?;
+                                    }
+                                }
                                 _ => {
-                                    println!("[{}] {}", addr, line);
+                                    debug!(command = next_split, "Ignoring unhandled message");
                                 }
                             }
                         }
                     }
-                    line.clear();
                 }
             };
         }
     }
 
-    pub async fn connect(
-        &mut self,
-        channels: Vec<String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let sasl_auth = self.password.len() > 0;
-
-        // Authenticate using SASL (only PLAIN is supported for now)
-        // Protocol info based on https://ircv3.net/specs/extensions/sasl-3.1
-        // TODO: handle SASL not being available, switch to nickserv as backup
-        if sasl_auth {
-            self.send_raw("CAP REQ :sasl\r\n").await?;
-        }
+    pub async fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.metrics.connected.store(true, Ordering::Relaxed);
 
+        // Capabilities (and SASL) are negotiated in the main loop, registration finishes with CAP END.
+        // Servers without capability support ignore CAP LS and register right away.
+        self.send_raw("CAP LS 302\r\n").await?;
         self.send_raw(&format!(
             "NICK {}\r\nUSER discord 8 *  : {}\r\n",
             self.nick, self.nick
         ))
         .await?;
 
-        if sasl_auth {
-            let sasl_plain = encode(format!(
-                "{}\x00{}\x00{}\x00",
-                self.nick, self.nick, self.password
-            ));
-            self.send_raw(&format!(
-                "AUTHENTICATE PLAIN\r\nAUTHENTICATE {}\r\nCAP END\r\n",
-                sasl_plain
-            ))
-            .await?;
-        }
+        let result = self.do_main_loop().await;
 
-        for channel in channels {
-            self.send_raw(&format!("JOIN {}\r\n", channel)).await?;
+        if self.puppet.is_some() {
+            self.puppet_nicks.remove(&self.addr, &self.nick);
         }
-        self.do_main_loop().await?;
-        Ok(())
+        result
     }
 }
 
-pub async fn connect_to_server(
-    addr: String,
+// Everything about a connection that comes from the config and the message log
+pub struct Session {
     nick: String,
     password: String,
     channels: Vec<String>,
+    log: Option<TextLog>,
+    cursors: HashMap<String, PlaybackCursor>,
+    edit_style: EditStyle,
+    reaction_fallback: String,
+    storage: Arc<Storage>,
+    away: Option<String>,
+    metrics: Arc<NetworkMetrics>,
+    puppet: Option<u64>,
+    puppet_nicks: PuppetNicks,
+    // Subscribed before connecting, so lines queued in the meantime aren't missed
+    rx: Receiver<message::BouncerMessage>,
+}
+
+impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> IRCSocket<T> {
+    fn new(
+        addr: String,
+        stream: T,
+        tx: Sender<message::BouncerMessage>,
+        session: Session,
+    ) -> IRCSocket<T> {
+        IRCSocket {
+            metrics: session.metrics,
+            addr,
+            stream: BufReader::new(stream),
+            tx,
+            nick: session.nick,
+            password: session.password,
+            log: session.log,
+            members: HashMap::new(),
+            prefixes: vec![('o', '@'), ('v', '+')],
+            chanmodes: ["beI", "k", "l", "imnpst"]
+                .iter()
+                .map(|g| String::from(*g))
+                .collect(),
+            channels: session.channels,
+            available_caps: Vec::new(),
+            caps: HashSet::new(),
+            cursors: session.cursors,
+            batches: HashMap::new(),
+            played: HashMap::new(),
+            history_limit: HISTORY_PAGE_SIZE,
+            storage: session.storage,
+            edit_style: session.edit_style,
+            reaction_fallback: session.reaction_fallback,
+            pending_edits: HashMap::new(),
+            last_edit: HashMap::new(),
+            typing: HashMap::new(),
+            away: session.away,
+            topics: HashMap::new(),
+            registered: false,
+            puppet: session.puppet,
+            puppet_nicks: session.puppet_nicks,
+            rx: Some(session.rx),
+            quitting: false,
+        }
+    }
+
+    // What the next connection picks up from, once this one is lost
+    fn into_session(self) -> Session {
+        Session {
+            rx: self.tx.subscribe(),
+            nick: self.nick,
+            password: self.password,
+            channels: self.channels,
+            log: self.log,
+            cursors: self.cursors,
+            edit_style: self.edit_style,
+            reaction_fallback: self.reaction_fallback,
+            storage: self.storage,
+            away: self.away,
+            metrics: self.metrics,
+            puppet: self.puppet,
+            puppet_nicks: self.puppet_nicks,
+        }
+    }
+
+    async fn run(mut self) -> (Result<(), String>, Session) {
+        let result = self.connect().await.map_err(|why| why.to_string());
+        (result, self.into_session())
+    }
+}
+
+// Hands the session back along with how the connection ended, so it can be reconnected
+pub async fn connect_to_server(
+    addr: String,
     use_tls: bool,
     tx: Sender<message::BouncerMessage>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let socket_connection = TcpStream::connect(&addr).await?;
+    session: Session,
+) -> (Result<(), String>, Session) {
+    let socket_connection = match TcpStream::connect(&addr).await {
+        Ok(socket_connection) => socket_connection,
+        Err(why) => return (Err(why.to_string()), session),
+    };
 
     if !use_tls {
         // Can just short-circuit with the existing stream
-        return IRCSocket {
-            addr,
-            tls: use_tls,
-            stream: BufReader::new(socket_connection),
-            tx,
-            nick,
-            password,
+        return IRCSocket::new(addr, socket_connection, tx, session)
+            .run()
+            .await;
+    }
+
+    let connector = match TlsConnector::builder().build() {
+        Ok(connector) => tokio_native_tls::TlsConnector::from(connector),
+        Err(why) => return (Err(why.to_string()), session),
+    };
+    let stream = match connector
+        .connect(addr.split(":").next().unwrap(), socket_connection)
+        .await
+    {
+        Ok(stream) => stream,
+        Err(why) => return (Err(why.to_string()), session),
+    };
+
+    IRCSocket::new(addr, stream, tx, session).run().await
+}
+
+// Waits out the delay before reconnecting, keeping up with what was sent to the network meanwhile.
+// Returns false when asked to QUIT instead
+async fn wait_to_reconnect(session: &mut Session, addr: &str, delay: Duration) -> bool {
+    let deadline = sleep_until(Instant::now() + delay);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => return true,
+            received = session.rx.recv() => match received {
+                Ok(cmd) if cmd.network == addr && cmd.puppet == session.puppet => match cmd.state {
+                    message::MessageState::COMMAND if is_quit(&cmd.content) => return false,
+                    message::MessageState::COMMAND => {
+                        if let Some(channel) = cmd.content.strip_prefix("JOIN ") {
+                            session.channels.push(String::from(channel));
+                        } else if let Some(channel) = cmd.content.strip_prefix("PART ") {
+                            session.channels.retain(|c| !c.eq_ignore_ascii_case(channel));
+                        }
+                    }
+                    message::MessageState::AWAY => {
+                        session.away = Some(cmd.content).filter(|away| !away.is_empty());
+                    }
+                    message::MessageState::OUTGOING => {
+                        warn!("Dropping a message to {} sent while disconnected", cmd.channel);
+                    }
+                    _ => {}
+                },
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return false,
+            }
         }
-        .connect(channels)
-        .await;
     }
+}
 
-    let stream = BufReader::new(
-        tokio_native_tls::TlsConnector::from(TlsConnector::builder().build()?)
-            .connect(&addr.split(":").next().unwrap(), socket_connection)
-            .await?,
-    );
+pub fn spawn_connection(
+    server: &IRCServerConfig,
+    tx: Sender<message::BouncerMessage>,
+    logs_dir: &Path,
+    storage: Arc<Storage>,
+    away: Option<String>,
+    puppet_nicks: PuppetNicks,
+) -> JoinHandle<()> {
+    let server_addr = String::from(&server.address);
 
-    return IRCSocket {
-        addr,
-        tls: use_tls,
-        stream,
-        tx,
+    let use_tls = server.tls;
+
+    let mut chans: Vec<String> = Vec::new();
+
+    for chan in &server.channels {
+        chans.push(String::from(&chan.name));
+    }
+
+    let session = Session {
+        nick: String::from(&server.nick),
+        password: match &server.password {
+            Some(passwd) => String::from(passwd.expose()),
+            None => String::new(),
+        },
+        channels: chans,
+        log: server
+            .log_format
+            .map(|format| TextLog::new(logs_dir, &server_addr, format)),
+        // Loaded once the session starts
+        cursors: HashMap::new(),
+        edit_style: server.edit_style.unwrap_or(EditStyle::Correction),
+        reaction_fallback: server
+            .reaction_fallback
+            .as_deref()
+            .unwrap_or(DEFAULT_REACTION_FALLBACK)
+            .to_string(),
+        storage,
+        away,
+        metrics: METRICS.network(&server_addr),
+        puppet: None,
+        puppet_nicks,
+        rx: tx.subscribe(),
+    };
+
+    let span = info_span!("network", addr = %server_addr);
+    spawn_session(server_addr, use_tls, tx, session, span)
+}
+
+// Connects on behalf of a single Discord user, who only ever talks and never relays anything back
+pub fn spawn_puppet(
+    server: &IRCServerConfig,
+    tx: Sender<message::BouncerMessage>,
+    storage: Arc<Storage>,
+    puppet: u64,
+    nick: String,
+    password: Option<String>,
+    puppet_nicks: PuppetNicks,
+) -> JoinHandle<()> {
+    let server_addr = String::from(&server.address);
+
+    let session = Session {
         nick,
-        password,
+        password: password.unwrap_or_default(),
+        channels: server
+            .channels
+            .iter()
+            .map(|chan| chan.name.clone())
+            .collect(),
+        log: None,
+        cursors: HashMap::new(),
+        edit_style: server.edit_style.unwrap_or(EditStyle::Correction),
+        reaction_fallback: DEFAULT_REACTION_FALLBACK.to_string(),
+        storage,
+        away: None,
+        // Kept out of the exported metrics, which describe the bridge's own connections
+        metrics: Arc::new(NetworkMetrics::default()),
+        puppet: Some(puppet),
+        puppet_nicks,
+        rx: tx.subscribe(),
+    };
+
+    let span = info_span!("puppet", addr = %server_addr, user = puppet);
+    spawn_session(server_addr, server.tls, tx, session, span)
+}
+
+fn spawn_session(
+    server_addr: String,
+    use_tls: bool,
+    tx: Sender<message::BouncerMessage>,
+    session: Session,
+    span: tracing::Span,
+) -> JoinHandle<()> {
+    let metrics = Arc::clone(&session.metrics);
+    let mut session = session;
+    let mut delay = RECONNECT_DELAY;
+
+    tokio::spawn(
+        async move {
+            // Only the main connection plays history back
+            if session.puppet.is_none() {
+                let addr = server_addr.clone();
+                session.cursors = session
+                    .storage
+                    .blocking(move |storage| storage.cursors(&addr))
+                    .await
+                    .unwrap_or_else(|why| {
+                        error!(
+                            "Unable to load playback cursors for {}: {}",
+                            server_addr, why
+                        );
+                        HashMap::new()
+                    });
+            }
+
+            loop {
+                let generation = metrics.connections.fetch_add(1, Ordering::Relaxed) + 1;
+                let (result, next) =
+                    connect_to_server(server_addr.clone(), use_tls, tx.clone(), session).await;
+                session = next;
+
+                let registered = metrics.registered.load(Ordering::Relaxed);
+
+                // A reload may have replaced this connection with one that shares the metrics
+                if metrics.connections.load(Ordering::Relaxed) == generation {
+                    metrics.connected.store(false, Ordering::Relaxed);
+                    metrics.registered.store(false, Ordering::Relaxed);
+                }
+
+                match result {
+                    Ok(()) => {
+                        info!("Disconnected");
+                        return;
+                    }
+                    Err(why) => error!("Connection failed: {}", why),
+                }
+
+                if registered {
+                    delay = RECONNECT_DELAY;
+                }
+                info!("Reconnecting in {}s", delay.as_secs());
+
+                if !wait_to_reconnect(&mut session, &server_addr, delay).await {
+                    info!("Disconnected");
+                    return;
+                }
+                delay = min(delay * 2, MAX_RECONNECT_DELAY);
+            }
+        }
+        .instrument(span),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_line_joins_lines() {
+        assert_eq!(
+            single_line("one\ntwo https://example.com"),
+            "one two https://example.com"
+        );
+        assert_eq!(single_line(&single_line("a\nb")), "a b");
+        assert_eq!(single_line("a\r\nb\rc"), "a b c");
+    }
+
+    #[test]
+    fn part_end_keeps_characters_whole() {
+        let text = "aé€b";
+        assert_eq!(part_end(text, 0, 10), text.len());
+        assert_eq!(part_end(text, 0, 2), 1);
+        assert_eq!(part_end(text, 1, 2), 3);
+        assert_eq!(part_end(text, 3, 2), 6);
+        assert_eq!(&text[part_end(text, 0, 4)..], "€b");
+    }
+
+    #[test]
+    fn correction_line_sed_whole_word() {
+        assert_eq!(
+            correction_line(EditStyle::Sed, "hello wrold", "hello world"),
+            "s/wrold/world/"
+        );
+    }
+
+    #[test]
+    fn correction_line_sed_multibyte() {
+        assert_eq!(
+            correction_line(EditStyle::Sed, "café au lait", "cafés au lait"),
+            "s/café/cafés/"
+        );
+    }
+
+    #[test]
+    fn correction_line_sed_falls_back_on_slash() {
+        assert_eq!(
+            correction_line(EditStyle::Sed, "see a/b", "see a/c"),
+            "* correction: see a/c"
+        );
+    }
+
+    #[test]
+    fn correction_line_sed_falls_back_on_nothing_removed() {
+        assert_eq!(
+            correction_line(EditStyle::Sed, "", "hi"),
+            "* correction: hi"
+        );
+    }
+
+    #[test]
+    fn correction_line_correction_style() {
+        assert_eq!(
+            correction_line(EditStyle::Correction, "hello wrold", "hello world"),
+            "* correction: hello world"
+        );
     }
-    .connect(channels)
-    .await;
 }
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::{LogFormat, LogLevel};
+
+// Every line sent to or received from a network is logged at trace level under this target
+pub const RAW_IRC: &str = "irc_discord_bouncer::raw";
+
+static LOG_MESSAGE_BODIES: AtomicBool = AtomicBool::new(false);
+
+pub fn init(level: LogLevel, format: LogFormat, message_bodies: bool) {
+    LOG_MESSAGE_BODIES.store(message_bodies, Ordering::Relaxed);
+
+    let level = match level {
+        LogLevel::Error => "error",
+        LogLevel::Warn => "warn",
+        LogLevel::Info => "info",
+        LogLevel::Debug => "debug",
+        LogLevel::Trace => "trace",
+    };
+
+    // Dependencies (serenity in particular) are very chatty, so they only get warnings by default.
+    // RUST_LOG takes precedence for finer-grained control.
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("warn,irc_discord_bouncer={}", level)));
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+// Message contents are private, so they only show up in logs when explicitly enabled
+pub fn redact(content: &str) -> &str {
+    match LOG_MESSAGE_BODIES.load(Ordering::Relaxed) {
+        true => content,
+        false => "<redacted>",
+    }
+}
+
+// SASL payloads contain the account password, so they are never logged
+pub fn redact_raw(line: &str) -> &str {
+    match line.strip_prefix("AUTHENTICATE ") {
+        Some("PLAIN") | Some("+") | None => line,
+        Some(_) => "AUTHENTICATE <redacted>",
+    }
+}
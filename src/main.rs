@@ -1,62 +1,187 @@
 #[macro_use]
 extern crate simple_error;
+mod avatar;
+mod cli;
+mod config;
 mod discord;
+mod health;
+mod http;
 mod irc;
+mod logging;
 mod message;
+mod metrics;
+mod reload;
+mod shutdown;
+mod storage;
+mod textlog;
 
-use tokio::sync::broadcast;
-
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize)]
-struct Config {
-    token: String,
-    discord_user_id: u64,
-    servers: Vec<discord::IRCServerConfig>,
-}
-
+use clap::Parser;
 use std::fs;
+use std::process;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+use tracing::{debug, error, info};
 
 #[tokio::main]
 async fn main() {
-    let data: Config =
-        serde_json::from_str(&fs::read_to_string("config.json").expect("Failed to load config"))
-            .unwrap();
-    let (tx, mut rx) = broadcast::channel(32);
-
-    for server in &data.servers {
-        tokio::spawn({
-            let tx_irc = tx.clone();
-            let server_addr = String::from(&server.address);
-            let nick = String::from(&server.nick);
-            let channels = &server.channels;
-            let use_tls = server.tls;
-            let password = match &server.password {
-                Some(passwd) => String::from(passwd),
-                None => String::new(),
-            };
-
-            let mut chans: Vec<String> = Vec::new();
-
-            for chan in channels {
-                chans.push(String::from(&chan.name));
-            }
+    let cli = cli::Cli::parse();
 
-            async move {
-                irc::connect_to_server(server_addr, nick, password, chans, use_tls, tx_irc)
-                    .await
-                    .unwrap();
+    match cli.command {
+        Some(cli::Command::Validate) => process::exit(cli::validate(&cli.config)),
+        Some(cli::Command::GenConfig {
+            output,
+            format,
+            interactive,
+            force,
+        }) => process::exit(cli::gen_config(output, format, interactive, force)),
+        Some(cli::Command::Run) | None => {
+            if cli.check_config {
+                process::exit(cli::validate(&cli.config));
             }
-        });
+        }
     }
 
+    logging::init(cli.log_level, cli.log_format, cli.log_message_bodies);
+
+    let data = match config::Config::load(&cli.config) {
+        Ok(data) => data,
+        Err(why) => {
+            error!("{}", why);
+            process::exit(1);
+        }
+    };
+
+    if let Err(why) = fs::create_dir_all(&cli.data_dir) {
+        error!(
+            "Unable to create data directory {}: {}",
+            cli.data_dir.display(),
+            why
+        );
+        process::exit(1);
+    }
+
+    // Roomy enough for pages of history playback arriving faster than webhooks can deliver them
+    let (tx, mut rx) = broadcast::channel(1024);
+
     let tx_discord = tx.clone();
 
     tokio::spawn(async move {
         while let Ok(cmd) = rx.recv().await {
-            println!("{}", cmd);
+            debug!("{}", cmd);
+        }
+    });
+
+    let db_path = cli.data_dir.join("messages.db");
+    let storage = match storage::Storage::open(&db_path) {
+        Ok(storage) => storage,
+        Err(why) => {
+            error!("Unable to open {}: {}", db_path.display(), why);
+            process::exit(1);
         }
+    };
+
+    let state = Arc::new(
+        discord::BridgeState::new(
+            data,
+            cli.config,
+            tx_discord,
+            storage,
+            cli.data_dir.join("logs"),
+        )
+        .expect("Invalid config"),
+    );
+
+    for server in &state.config.lock().await.servers {
+        state.connect(server).await;
+    }
+
+    if let Some(http_address) = &state.config.lock().await.http_address {
+        // Already validated as part of the config
+        tokio::spawn(http::serve(http_address.parse().unwrap(), state.clone()));
+    }
+
+    tokio::spawn({
+        let state = state.clone();
+        async move { health::systemd_watchdog(&state).await }
     });
 
-    discord::discord_init(&data.token, data.discord_user_id, data.servers, tx_discord).await;
+    // Apply the retention policy hourly, re-reading it so config reloads take effect
+    tokio::spawn({
+        let state = state.clone();
+        let mut ticker = interval(Duration::from_secs(60 * 60));
+
+        async move {
+            loop {
+                ticker.tick().await;
+
+                let retention_days = state.config.lock().await.message_retention_days;
+                if let Some(days) = retention_days {
+                    let retention = Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+
+                    match state
+                        .storage
+                        .blocking(move |storage| storage.prune(retention))
+                        .await
+                    {
+                        Ok(0) => {}
+                        Ok(pruned) => {
+                            info!("Pruned {} messages older than {} days", pruned, days)
+                        }
+                        Err(why) => error!("Unable to prune the message log: {}", why),
+                    }
+                }
+            }
+        }
+    });
+
+    // Puppets of Discord users who have gone quiet are disconnected until they talk again
+    tokio::spawn({
+        let state = state.clone();
+        let mut ticker = interval(Duration::from_secs(60));
+
+        async move {
+            loop {
+                ticker.tick().await;
+                state.disconnect_idle_puppets().await;
+            }
+        }
+    });
+
+    // Re-read the config on SIGHUP and apply the differences to the running bouncer
+    tokio::spawn({
+        let state = state.clone();
+        let mut sighup = signal(SignalKind::hangup()).expect("Failed to register SIGHUP handler");
+
+        async move {
+            while sighup.recv().await.is_some() {
+                match reload::reload_config(&state).await {
+                    Ok(()) => info!("Reloaded {}", state.config_path),
+                    Err(why) => error!("Rejected config reload: {}", why),
+                }
+            }
+        }
+    });
+
+    let mut client = discord::discord_init(state.clone()).await;
+    let shard_manager = client.shard_manager.clone();
+
+    tokio::select! {
+        result = client.start() => {
+            if let Err(why) = result {
+                error!("An error occurred while running the client: {:?}", why);
+            }
+        },
+        _ = shutdown::wait_for_signal() => {
+            info!("Shutting down");
+            let drained = shutdown::shutdown(&state).await;
+            shard_manager.lock().await.shutdown_all().await;
+
+            if !drained {
+                error!("Not every queued message was delivered before the shutdown deadline");
+                process::exit(1);
+            }
+        },
+    }
 }
@@ -1,9 +1,35 @@
 use std::fmt::{Display, Formatter};
+use std::time::SystemTime;
 
+use crate::logging;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 pub enum MessageState {
     INCOMING,
     OUTGOING,
+    // A raw IRC line (JOIN, PART, ...) to be sent as-is to the network
+    COMMAND,
+    // An edit of an OUTGOING message, carrying its new content
+    EDIT,
+    // A deletion of an OUTGOING message
+    DELETE,
+    // An INCOMING message was redacted on IRC, identified by its msgid
+    REDACT,
+    // A reaction from Discord, with the emoji as content and the message reacted to in reply_to
+    REACT,
+    // A reaction on IRC, with the emoji as content and the message reacted to in reply_to
+    REACTION,
+    // The owner is typing in Discord
+    TYPING,
+    // A +typing status from IRC, with the status as content
+    TYPINGSTATUS,
+    // Sets the away message given as content, or marks us back when it is empty
+    AWAY,
+    // A channel topic from IRC, as playback when it isn't a change worth announcing
+    TOPIC,
+    // Marks the end of the queue on shutdown, everything sent before it has been delivered once it is seen
+    SHUTDOWN,
 }
 
 impl Copy for MessageState {}
@@ -13,6 +39,14 @@ impl Clone for MessageState {
     }
 }
 
+// The message being replied to. Replies from IRC only carry the msgid, replies from
+// Discord also carry the nick for networks that don't support reply tags.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub msgid: Option<String>,
+    pub nick: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct BouncerMessage {
     pub network: String,
@@ -21,6 +55,43 @@ pub struct BouncerMessage {
     pub content: String,
     pub state: MessageState,
     pub ping: bool,
+    // IRCv3 msgid tag, when the network sends it
+    pub msgid: Option<String>,
+    // When the message was sent on IRC, from the server-time tag or else when it was received
+    pub time: Option<SystemTime>,
+    // Part of a history playback after the bouncer was away
+    pub playback: bool,
+    pub reply_to: Option<Reply>,
+    // The Discord message an OUTGOING message or edit came from
+    pub discord_message_id: Option<u64>,
+    // Services account of the sender, from the IRCv3 account tag
+    pub account: Option<String>,
+    // The sender's highest channel status, e.g. @ for ops
+    pub prefix: Option<char>,
+    // The Discord user whose puppet connection sends an OUTGOING message or command
+    pub puppet: Option<u64>,
+}
+
+impl BouncerMessage {
+    // An empty message in the given state, to fill in with struct update syntax
+    pub fn new(state: MessageState) -> BouncerMessage {
+        BouncerMessage {
+            network: String::new(),
+            channel: String::new(),
+            user: String::new(),
+            content: String::new(),
+            state,
+            ping: false,
+            msgid: None,
+            time: None,
+            playback: false,
+            reply_to: None,
+            discord_message_id: None,
+            account: None,
+            prefix: None,
+            puppet: None,
+        }
+    }
 }
 
 impl Clone for BouncerMessage {
@@ -32,6 +103,14 @@ impl Clone for BouncerMessage {
             content: self.content.clone(),
             state: self.state,
             ping: self.ping,
+            msgid: self.msgid.clone(),
+            time: self.time,
+            playback: self.playback,
+            reply_to: self.reply_to.clone(),
+            discord_message_id: self.discord_message_id,
+            account: self.account.clone(),
+            prefix: self.prefix,
+            puppet: self.puppet,
         }
     }
 }
@@ -41,7 +120,11 @@ impl Display for BouncerMessage {
         write!(
             formatter,
             "BouncerMessage({}, {}, {}, {}, {:?})",
-            self.network, self.channel, self.user, self.content, self.state
+            self.network,
+            self.channel,
+            self.user,
+            logging::redact(&self.content),
+            self.state
         )?;
         Ok(())
     }
@@ -0,0 +1,206 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+pub struct NetworkMetrics {
+    pub connected: AtomicBool,
+    pub registered: AtomicBool,
+    pub connections: AtomicU64,
+    pub lines_in: AtomicU64,
+    pub lines_out: AtomicU64,
+    pub send_queue: AtomicU64,
+}
+
+// Upper bounds in seconds, Discord's API usually answers well within a second
+const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let count = self.count.load(Ordering::Relaxed);
+
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    networks: RwLock<HashMap<String, Arc<NetworkMetrics>>>,
+    pub webhook_latency: Histogram,
+    pub webhook_rate_limited: AtomicU64,
+    pub delivery_failures: AtomicU64,
+    pub dead_letters: AtomicU64,
+    pub broadcast_lagged: AtomicU64,
+}
+
+// Name, type, help text and how to read the value
+type MetricDescription<'a, T> = (&'a str, &'a str, &'a str, T);
+type NetworkValue = fn(&NetworkMetrics) -> u64;
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    pub fn network(&self, addr: &str) -> Arc<NetworkMetrics> {
+        if let Some(network) = self.networks.read().unwrap().get(addr) {
+            return network.clone();
+        }
+
+        self.networks
+            .write()
+            .unwrap()
+            .entry(String::from(addr))
+            .or_default()
+            .clone()
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let networks = self.networks.read().unwrap();
+        let mut addrs: Vec<&String> = networks.keys().collect();
+        addrs.sort();
+
+        let per_network: [MetricDescription<NetworkValue>; 6] = [
+            (
+                "bouncer_irc_connected",
+                "gauge",
+                "Whether the bouncer is currently connected to the network",
+                |n| n.connected.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "bouncer_irc_registered",
+                "gauge",
+                "Whether registration with the network has completed (001 received)",
+                |n| n.registered.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "bouncer_irc_connections_total",
+                "counter",
+                "Connection attempts made to the network, anything above 1 is a reconnect",
+                |n| n.connections.load(Ordering::Relaxed),
+            ),
+            (
+                "bouncer_irc_lines_received_total",
+                "counter",
+                "Lines received from the network",
+                |n| n.lines_in.load(Ordering::Relaxed),
+            ),
+            (
+                "bouncer_irc_lines_sent_total",
+                "counter",
+                "Lines sent to the network",
+                |n| n.lines_out.load(Ordering::Relaxed),
+            ),
+            (
+                "bouncer_irc_send_queue_depth",
+                "gauge",
+                "Messages waiting to be processed by the network's connection",
+                |n| n.send_queue.load(Ordering::Relaxed),
+            ),
+        ];
+
+        for (name, kind, help, value) in per_network.iter() {
+            header(&mut out, name, kind, help);
+            for addr in &addrs {
+                let _ = writeln!(
+                    out,
+                    "{}{{network=\"{}\"}} {}",
+                    name,
+                    addr,
+                    value(&networks[*addr])
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "bouncer_discord_webhook_latency_seconds",
+            "histogram",
+            "Time taken to execute a Discord webhook",
+        );
+        self.webhook_latency
+            .render(&mut out, "bouncer_discord_webhook_latency_seconds");
+
+        let global: [MetricDescription<&AtomicU64>; 4] = [
+            (
+                "bouncer_discord_webhook_rate_limited_total",
+                "counter",
+                "Webhook executions rejected by Discord with 429 Too Many Requests",
+                &self.webhook_rate_limited,
+            ),
+            (
+                "bouncer_discord_delivery_failures_total",
+                "counter",
+                "Messages that could not be delivered to Discord after retrying",
+                &self.delivery_failures,
+            ),
+            (
+                "bouncer_discord_dead_letters",
+                "gauge",
+                "Undeliverable messages currently held in the dead-letter queue",
+                &self.dead_letters,
+            ),
+            (
+                "bouncer_discord_broadcast_lagged_total",
+                "counter",
+                "Messages dropped because the Discord delivery loop fell behind the message bus",
+                &self.broadcast_lagged,
+            ),
+        ];
+
+        for (name, kind, help, value) in global.iter() {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
@@ -0,0 +1,93 @@
+use crate::config::{Config, IRCServerConfig};
+use crate::discord::{BridgeMaps, BridgeState};
+use tracing::warn;
+
+// Nick, password and TLS are fixed once a socket is registered, and the log format, edit style and
+// reaction fallback are picked when connecting, so changing any of them means reconnecting
+fn same_connection(old: &IRCServerConfig, new: &IRCServerConfig) -> bool {
+    old.tls == new.tls
+        && old.nick == new.nick
+        && old.password == new.password
+        && old.log_format == new.log_format
+        && old.edit_style == new.edit_style
+        && old.reaction_fallback == new.reaction_fallback
+}
+
+fn has_channel(server: &IRCServerConfig, name: &str) -> bool {
+    server
+        .channels
+        .iter()
+        .any(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+pub async fn reload_config(
+    state: &BridgeState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Parse and validate everything up front so a bad config leaves the running bouncer untouched
+    let new_config = Config::load(&state.config_path)?;
+    let maps = BridgeMaps::from_config(&new_config.servers)?;
+
+    let mut config = state.config.lock().await;
+
+    if new_config.token != config.token || new_config.discord_user_id != config.discord_user_id {
+        warn!("Changes to token and discord_user_id require a restart");
+    }
+
+    // The presence intent is only asked for when connecting to Discord
+    if new_config.away.is_some() && config.away.is_none() {
+        warn!("Enabling away requires a restart");
+    }
+
+    for old in &config.servers {
+        match new_config.servers.iter().find(|s| s.address == old.address) {
+            Some(new) if same_connection(old, new) => {
+                for chan in new.channels.iter().filter(|c| !has_channel(old, &c.name)) {
+                    state.send_irc_command(
+                        &old.address,
+                        &chan.name,
+                        format!("JOIN {}", chan.name),
+                    )?;
+                    state
+                        .send_puppet_command(&old.address, format!("JOIN {}", chan.name))
+                        .await;
+                }
+
+                for chan in old.channels.iter().filter(|c| !has_channel(new, &c.name)) {
+                    state.send_irc_command(
+                        &old.address,
+                        &chan.name,
+                        format!("PART {}", chan.name),
+                    )?;
+                    state
+                        .send_puppet_command(&old.address, format!("PART {}", chan.name))
+                        .await;
+                }
+            }
+            _ => {
+                state.connections.lock().await.remove(&old.address);
+                state.send_irc_command(
+                    &old.address,
+                    "",
+                    "QUIT :Configuration reloaded".to_string(),
+                )?;
+                state
+                    .quit_network_puppets(&old.address, "Configuration reloaded")
+                    .await;
+            }
+        }
+    }
+
+    // Connect any new networks, and reconnect the ones whose connection settings changed
+    for new in &new_config.servers {
+        match config.servers.iter().find(|s| s.address == new.address) {
+            Some(old) if same_connection(old, new) => {}
+            _ => state.connect(new).await,
+        }
+    }
+
+    // Webhooks are looked up from the maps on every delivery, so swapping them is enough to pick up changes
+    *state.maps.write().await = maps;
+    *config = new_config;
+
+    Ok(())
+}
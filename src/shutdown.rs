@@ -0,0 +1,69 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{timeout_at, Duration, Instant};
+use tracing::{error, warn};
+
+use crate::discord::BridgeState;
+use crate::message;
+
+const DEFAULT_QUIT_MESSAGE: &str = "Bouncer shutting down";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+pub async fn wait_for_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}
+
+// Disconnects from every network and waits for the messages they already sent to reach Discord.
+// Returns whether that finished before the deadline.
+pub async fn shutdown(state: &BridgeState) -> bool {
+    let (quit_message, deadline) = {
+        let config = state.config.lock().await;
+        (
+            config
+                .quit_message
+                .clone()
+                .unwrap_or_else(|| DEFAULT_QUIT_MESSAGE.to_string()),
+            Instant::now()
+                + Duration::from_secs(
+                    config
+                        .shutdown_timeout_secs
+                        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+                ),
+        )
+    };
+
+    let mut connections: Vec<_> = state.connections.lock().await.drain().collect();
+
+    for (addr, _) in &connections {
+        if let Err(why) = state.send_irc_command(addr, "", format!("QUIT :{}", quit_message)) {
+            error!("Unable to send QUIT to {}: {}", addr, why);
+        }
+    }
+    connections.extend(state.quit_puppets(&quit_message).await);
+
+    // Networks close the connection once they have processed our QUIT, after which
+    // nothing else can be queued for delivery
+    for (addr, handle) in connections {
+        if timeout_at(deadline, handle).await.is_err() {
+            warn!("{} did not close the connection in time", addr);
+        }
+    }
+
+    // The delivery loop handles messages in order, so once it reaches this marker everything
+    // queued before it has been delivered
+    if state
+        .irc_tx
+        .send(message::BouncerMessage::new(
+            message::MessageState::SHUTDOWN,
+        ))
+        .is_err()
+    {
+        return false;
+    }
+
+    timeout_at(deadline, state.drained.notified()).await.is_ok()
+}
@@ -0,0 +1,803 @@
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, ToSql};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::message::MessageState;
+
+// A relayed message, in either direction
+pub struct LoggedMessage<'a> {
+    pub network: &'a str,
+    pub channel: &'a str,
+    pub nick: &'a str,
+    pub content: &'a str,
+    pub direction: MessageState,
+    pub timestamp: SystemTime,
+    pub msgid: Option<&'a str>,
+    pub discord_message_id: Option<u64>,
+    pub discord_channel_id: Option<u64>,
+    // The Discord user whose puppet sent an outgoing message
+    pub puppet: Option<u64>,
+}
+
+// Every filter is optional, an empty query matches everything
+#[derive(Default)]
+pub struct SearchQuery {
+    pub text: Option<String>,
+    pub nick: Option<String>,
+    pub channel: Option<String>,
+    pub network: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+pub struct SearchResult {
+    pub network: String,
+    pub channel: String,
+    pub nick: String,
+    pub content: String,
+    pub timestamp: SystemTime,
+    pub discord_message_id: Option<u64>,
+    pub discord_channel_id: Option<u64>,
+}
+
+// Both sides of a relayed message, for matching up replies
+pub struct RelayedMessage {
+    pub msgid: Option<String>,
+    pub nick: String,
+    pub content: String,
+    pub discord_message_id: Option<u64>,
+    pub discord_channel_id: Option<u64>,
+    pub puppet: Option<u64>,
+}
+
+// The last message relayed from a target, where history playback resumes after a reconnect
+#[derive(Clone)]
+pub struct PlaybackCursor {
+    pub msgid: Option<String>,
+    pub timestamp: SystemTime,
+}
+
+pub struct Storage {
+    conn: Mutex<Connection>,
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as i64)
+}
+
+fn from_unix_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    Ok(columns.any(|name| name.is_ok_and(|name| name == column)))
+}
+
+// Quotes every word so FTS5 operators in user input are matched literally
+fn fts_phrase(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl Storage {
+    pub fn open(path: &Path) -> rusqlite::Result<Storage> {
+        let conn = Connection::open(path)?;
+
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                network TEXT NOT NULL,
+                channel TEXT NOT NULL,
+                nick TEXT NOT NULL,
+                content TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                -- Milliseconds since the unix epoch
+                timestamp INTEGER NOT NULL,
+                msgid TEXT,
+                discord_message_id INTEGER
+            );
+
+            CREATE INDEX IF NOT EXISTS messages_by_target ON messages (network, channel, timestamp);
+            CREATE INDEX IF NOT EXISTS messages_by_timestamp ON messages (timestamp);
+            CREATE INDEX IF NOT EXISTS messages_by_msgid ON messages (msgid);
+            CREATE INDEX IF NOT EXISTS messages_by_discord_id ON messages (discord_message_id);",
+        )?;
+
+        // Targets are stored lowercased since IRC names are case-insensitive
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS playback_cursors (
+                network TEXT NOT NULL,
+                target TEXT NOT NULL,
+                msgid TEXT,
+                timestamp INTEGER NOT NULL,
+                PRIMARY KEY (network, target)
+            );",
+        )?;
+
+        // The msgids of the IRC lines each outgoing Discord message was sent as, long ones are split
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS irc_parts (
+                network TEXT NOT NULL,
+                discord_message_id INTEGER NOT NULL,
+                msgid TEXT NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS irc_parts_by_discord_id ON irc_parts (discord_message_id);",
+        )?;
+
+        // Databases created before search was added lack the Discord channel
+        if !has_column(&conn, "messages", "discord_channel_id")? {
+            conn.execute_batch("ALTER TABLE messages ADD COLUMN discord_channel_id INTEGER;")?;
+        }
+
+        // Or puppets
+        if !has_column(&conn, "messages", "puppet")? {
+            conn.execute_batch("ALTER TABLE messages ADD COLUMN puppet INTEGER;")?;
+        }
+
+        let fts_exists = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'messages_fts'")?
+            .exists([])?;
+
+        // Full-text index over message contents, kept in sync with the messages table by triggers
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+                content, content = 'messages', content_rowid = 'id'
+            );
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;",
+        )?;
+
+        // Index whatever was logged before the index existed
+        if !fts_exists {
+            conn.execute_batch("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');")?;
+        }
+
+        Ok(Storage {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Runs queries on a blocking thread. They wait on the one connection, which a prune or a
+    // search can hold for a while, and that shouldn't stall the async workers
+    pub async fn blocking<T, F>(
+        self: &Arc<Self>,
+        query: F,
+    ) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        F: FnOnce(&Storage) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = Arc::clone(self);
+        Ok(tokio::task::spawn_blocking(move || query(&storage)).await??)
+    }
+
+    pub fn record(&self, message: &LoggedMessage) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "INSERT INTO messages (network, channel, nick, content, direction, timestamp, msgid, discord_message_id, discord_channel_id, puppet)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                message.network,
+                message.channel,
+                message.nick,
+                message.content,
+                match message.direction {
+                    MessageState::OUTGOING => "outgoing",
+                    _ => "incoming",
+                },
+                unix_millis(message.timestamp),
+                message.msgid,
+                // Discord snowflakes fit comfortably in 63 bits
+                message.discord_message_id.map(|id| id as i64),
+                message.discord_channel_id.map(|id| id as i64),
+                message.puppet.map(|id| id as i64),
+            ],
+        )?;
+        let id = conn.last_insert_rowid();
+
+        // Only ever move a cursor forward, messages can be relayed out of order around a reconnect
+        if message.direction == MessageState::INCOMING {
+            conn.execute(
+                "INSERT INTO playback_cursors (network, target, msgid, timestamp) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (network, target) DO UPDATE SET msgid = excluded.msgid, timestamp = excluded.timestamp
+                WHERE excluded.timestamp >= playback_cursors.timestamp",
+                params![
+                    message.network,
+                    message.channel.to_lowercase(),
+                    message.msgid,
+                    unix_millis(message.timestamp),
+                ],
+            )?;
+        }
+
+        Ok(id)
+    }
+
+    // Whether a message with this IRCv3 msgid was already relayed
+    pub fn has_msgid(&self, network: &str, msgid: &str) -> rusqlite::Result<bool> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM messages WHERE msgid = ?1 AND network = ?2 LIMIT 1",
+                params![msgid, network],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+    }
+
+    fn find_message(
+        &self,
+        condition: &str,
+        values: &[&dyn ToSql],
+    ) -> rusqlite::Result<Option<RelayedMessage>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!(
+                    "SELECT msgid, nick, content, discord_message_id, discord_channel_id, puppet
+                    FROM messages WHERE {} ORDER BY id DESC LIMIT 1",
+                    condition
+                ),
+                values,
+                |row| {
+                    Ok(RelayedMessage {
+                        msgid: row.get(0)?,
+                        nick: row.get(1)?,
+                        content: row.get(2)?,
+                        discord_message_id: row.get::<_, Option<i64>>(3)?.map(|id| id as u64),
+                        discord_channel_id: row.get::<_, Option<i64>>(4)?.map(|id| id as u64),
+                        puppet: row.get::<_, Option<i64>>(5)?.map(|id| id as u64),
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn find_by_msgid(
+        &self,
+        network: &str,
+        msgid: &str,
+    ) -> rusqlite::Result<Option<RelayedMessage>> {
+        self.find_message("msgid = ?1 AND network = ?2", &[&msgid, &network])
+    }
+
+    pub fn find_by_discord_id(&self, message_id: u64) -> rusqlite::Result<Option<RelayedMessage>> {
+        self.find_message("discord_message_id = ?1", &[&(message_id as i64)])
+    }
+
+    pub fn cursors(&self, network: &str) -> rusqlite::Result<HashMap<String, PlaybackCursor>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT target, msgid, timestamp FROM playback_cursors WHERE network = ?1")?;
+
+        let cursors = stmt
+            .query_map(params![network], |row| {
+                Ok((
+                    row.get(0)?,
+                    PlaybackCursor {
+                        msgid: row.get(1)?,
+                        timestamp: from_unix_millis(row.get(2)?),
+                    },
+                ))
+            })?
+            .collect();
+        cursors
+    }
+
+    // Deletes everything older than the retention period, returning how many messages were removed
+    pub fn prune(&self, retention: Duration) -> rusqlite::Result<usize> {
+        // Nothing can be older than a retention reaching back before 1970
+        let cutoff = match SystemTime::now().checked_sub(retention) {
+            Some(cutoff) => unix_millis(cutoff),
+            None => return Ok(0),
+        };
+        let conn = self.conn.lock().unwrap();
+
+        let pruned = conn.execute("DELETE FROM messages WHERE timestamp < ?1", params![cutoff])?;
+        conn.execute(
+            "DELETE FROM irc_parts WHERE discord_message_id NOT IN
+            (SELECT discord_message_id FROM messages WHERE discord_message_id IS NOT NULL)",
+            [],
+        )?;
+        Ok(pruned)
+    }
+
+    // Records the msgid of one of the IRC lines an outgoing Discord message was sent as
+    pub fn add_irc_part(
+        &self,
+        network: &str,
+        discord_message_id: u64,
+        msgid: &str,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "INSERT INTO irc_parts (network, discord_message_id, msgid) VALUES (?1, ?2, ?3)",
+            params![network, discord_message_id as i64, msgid],
+        )?;
+
+        // The first line stands in for the whole message, so IRC replies to it can be matched up
+        conn.execute(
+            "UPDATE messages SET msgid = ?1 WHERE discord_message_id = ?2 AND msgid IS NULL",
+            params![msgid, discord_message_id as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn irc_parts(
+        &self,
+        network: &str,
+        discord_message_id: u64,
+    ) -> rusqlite::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT msgid FROM irc_parts WHERE network = ?1 AND discord_message_id = ?2 ORDER BY rowid",
+        )?;
+
+        let parts = stmt
+            .query_map(params![network, discord_message_id as i64], |row| {
+                row.get(0)
+            })?
+            .collect();
+        parts
+    }
+
+    // Forgets the IRC lines of a message once they have been redacted
+    pub fn remove_irc_parts(&self, network: &str, discord_message_id: u64) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "DELETE FROM irc_parts WHERE network = ?1 AND discord_message_id = ?2",
+            params![network, discord_message_id as i64],
+        )?;
+        conn.execute(
+            "UPDATE messages SET msgid = NULL WHERE discord_message_id = ?1",
+            params![discord_message_id as i64],
+        )?;
+        Ok(())
+    }
+
+    // Forgets a message deleted in Discord once its IRC lines have been redacted
+    pub fn remove_sent(&self, network: &str, discord_message_id: u64) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "DELETE FROM irc_parts WHERE network = ?1 AND discord_message_id = ?2",
+            params![network, discord_message_id as i64],
+        )?;
+        conn.execute(
+            "DELETE FROM messages WHERE discord_message_id = ?1",
+            params![discord_message_id as i64],
+        )?;
+        Ok(())
+    }
+
+    // Removes a message redacted on IRC from the log, returning where it was relayed to
+    pub fn redact(&self, network: &str, msgid: &str) -> rusqlite::Result<Option<RelayedMessage>> {
+        let relayed = self.find_message(
+            "msgid = ?1 AND network = ?2 AND direction = 'incoming'",
+            &[&msgid, &network],
+        )?;
+
+        if relayed.is_some() {
+            self.conn.lock().unwrap().execute(
+                "DELETE FROM messages WHERE msgid = ?1 AND network = ?2 AND direction = 'incoming'",
+                params![msgid, network],
+            )?;
+        }
+        Ok(relayed)
+    }
+
+    pub fn update_content(&self, discord_message_id: u64, content: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE messages SET content = ?1 WHERE discord_message_id = ?2",
+            params![content, discord_message_id as i64],
+        )?;
+        Ok(())
+    }
+
+    // Returns one page of matches, newest first, along with the total number of matches
+    pub fn search(&self, query: &SearchQuery) -> rusqlite::Result<(Vec<SearchResult>, usize)> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+        if let Some(text) = &query.text {
+            conditions
+                .push("messages.id IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?)");
+            values.push(Box::new(fts_phrase(text)));
+        }
+
+        // IRC nicks and channels are case-insensitive
+        for (condition, value) in [
+            ("nick = ? COLLATE NOCASE", &query.nick),
+            ("channel = ? COLLATE NOCASE", &query.channel),
+            ("network = ?", &query.network),
+        ] {
+            if let Some(value) = value {
+                conditions.push(condition);
+                values.push(Box::new(value.clone()));
+            }
+        }
+
+        if let Some(since) = query.since {
+            conditions.push("timestamp >= ?");
+            values.push(Box::new(unix_millis(since)));
+        }
+
+        if let Some(until) = query.until {
+            conditions.push("timestamp < ?");
+            values.push(Box::new(unix_millis(until)));
+        }
+
+        let filter = match conditions.is_empty() {
+            true => String::new(),
+            false => format!("WHERE {}", conditions.join(" AND ")),
+        };
+
+        let conn = self.conn.lock().unwrap();
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM messages {}", filter),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+
+        values.push(Box::new(query.limit.min(i64::MAX as usize) as i64));
+        values.push(Box::new(query.offset.min(i64::MAX as usize) as i64));
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT network, channel, nick, content, timestamp, discord_message_id, discord_channel_id
+            FROM messages {} ORDER BY timestamp DESC LIMIT ? OFFSET ?",
+            filter
+        ))?;
+
+        let results = stmt
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(SearchResult {
+                    network: row.get(0)?,
+                    channel: row.get(1)?,
+                    nick: row.get(2)?,
+                    content: row.get(3)?,
+                    timestamp: from_unix_millis(row.get(4)?),
+                    discord_message_id: row.get::<_, Option<i64>>(5)?.map(|id| id as u64),
+                    discord_channel_id: row.get::<_, Option<i64>>(6)?.map(|id| id as u64),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<SearchResult>>>()?;
+
+        Ok((results, total as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Storage {
+        Storage::open(Path::new(":memory:")).unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn incoming<'a>(
+        channel: &'a str,
+        content: &'a str,
+        msgid: &'a str,
+        time: u64,
+    ) -> LoggedMessage<'a> {
+        LoggedMessage {
+            network: "irc.example.org:6697",
+            channel,
+            nick: "alice",
+            content,
+            direction: MessageState::INCOMING,
+            timestamp: at(time),
+            msgid: Some(msgid),
+            discord_message_id: Some(time),
+            discord_channel_id: Some(100),
+            puppet: None,
+        }
+    }
+
+    fn outgoing(content: &str, discord_message_id: u64, time: u64) -> LoggedMessage<'_> {
+        LoggedMessage {
+            network: "irc.example.org:6697",
+            channel: "#rust",
+            nick: "bouncer",
+            content,
+            direction: MessageState::OUTGOING,
+            timestamp: at(time),
+            msgid: None,
+            discord_message_id: Some(discord_message_id),
+            discord_channel_id: Some(100),
+            puppet: None,
+        }
+    }
+
+    #[test]
+    fn record_and_find() {
+        let storage = storage();
+        storage.record(&incoming("#rust", "hello", "a", 1)).unwrap();
+        storage.record(&outgoing("hi", 50, 2)).unwrap();
+
+        let found = storage
+            .find_by_msgid("irc.example.org:6697", "a")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (found.nick.as_str(), found.content.as_str()),
+            ("alice", "hello")
+        );
+        assert_eq!(found.discord_message_id, Some(1));
+        assert!(storage.find_by_msgid("other:6697", "a").unwrap().is_none());
+
+        let found = storage.find_by_discord_id(50).unwrap().unwrap();
+        assert_eq!((found.nick.as_str(), found.msgid), ("bouncer", None));
+        assert_eq!(found.puppet, None);
+
+        storage
+            .record(&LoggedMessage {
+                nick: "alice[d]",
+                puppet: Some(7),
+                ..outgoing("from a puppet", 51, 3)
+            })
+            .unwrap();
+        assert_eq!(
+            storage.find_by_discord_id(51).unwrap().unwrap().puppet,
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn has_msgid_is_per_network() {
+        let storage = storage();
+        storage.record(&incoming("#rust", "hello", "a", 1)).unwrap();
+
+        assert!(storage.has_msgid("irc.example.org:6697", "a").unwrap());
+        assert!(!storage.has_msgid("irc.example.org:6697", "b").unwrap());
+        assert!(!storage.has_msgid("other:6697", "a").unwrap());
+    }
+
+    #[test]
+    fn cursors_only_move_forward() {
+        let storage = storage();
+        storage
+            .record(&incoming("#Rust", "second", "b", 2))
+            .unwrap();
+        storage.record(&incoming("#rust", "first", "a", 1)).unwrap();
+        storage.record(&outgoing("mine", 50, 3)).unwrap();
+
+        let cursors = storage.cursors("irc.example.org:6697").unwrap();
+        assert_eq!(cursors.len(), 1);
+        let cursor = &cursors["#rust"];
+        assert_eq!(
+            (cursor.msgid.as_deref(), cursor.timestamp),
+            (Some("b"), at(2))
+        );
+        assert!(storage.cursors("other:6697").unwrap().is_empty());
+    }
+
+    #[test]
+    fn irc_parts_stand_in_for_the_message() {
+        let network = "irc.example.org:6697";
+        let storage = storage();
+        storage.record(&outgoing("a long message", 50, 1)).unwrap();
+        storage.add_irc_part(network, 50, "x").unwrap();
+        storage.add_irc_part(network, 50, "y").unwrap();
+
+        assert_eq!(storage.irc_parts(network, 50).unwrap(), ["x", "y"]);
+        // Replies to the first line find the message
+        assert_eq!(
+            storage
+                .find_by_msgid(network, "x")
+                .unwrap()
+                .unwrap()
+                .discord_message_id,
+            Some(50)
+        );
+
+        storage.remove_irc_parts(network, 50).unwrap();
+        assert!(storage.irc_parts(network, 50).unwrap().is_empty());
+        assert_eq!(storage.find_by_discord_id(50).unwrap().unwrap().msgid, None);
+
+        storage.add_irc_part(network, 50, "z").unwrap();
+        storage.remove_sent(network, 50).unwrap();
+        assert!(storage.irc_parts(network, 50).unwrap().is_empty());
+        assert!(storage.find_by_discord_id(50).unwrap().is_none());
+    }
+
+    #[test]
+    fn prune_removes_old_messages_and_their_parts() {
+        let network = "irc.example.org:6697";
+        let storage = storage();
+        storage.record(&outgoing("ancient", 50, 1)).unwrap();
+        storage.add_irc_part(network, 50, "x").unwrap();
+        storage
+            .record(&LoggedMessage {
+                timestamp: SystemTime::now(),
+                ..outgoing("recent", 51, 0)
+            })
+            .unwrap();
+        storage.add_irc_part(network, 51, "y").unwrap();
+
+        assert_eq!(storage.prune(Duration::from_secs(24 * 60 * 60)).unwrap(), 1);
+        assert!(storage.find_by_discord_id(50).unwrap().is_none());
+        assert!(storage.irc_parts(network, 50).unwrap().is_empty());
+        assert!(storage.find_by_discord_id(51).unwrap().is_some());
+        assert_eq!(storage.irc_parts(network, 51).unwrap(), ["y"]);
+
+        // Reaching back before 1970 keeps everything
+        assert_eq!(storage.prune(Duration::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn redact_only_removes_incoming_messages() {
+        let network = "irc.example.org:6697";
+        let storage = storage();
+        storage.record(&incoming("#rust", "oops", "a", 7)).unwrap();
+        storage.record(&outgoing("mine", 50, 2)).unwrap();
+        storage.add_irc_part(network, 50, "b").unwrap();
+
+        let redacted = storage.redact(network, "a").unwrap().unwrap();
+        assert_eq!(redacted.discord_message_id, Some(7));
+        assert!(!storage.has_msgid(network, "a").unwrap());
+        assert!(storage.redact(network, "a").unwrap().is_none());
+
+        assert!(storage.redact(network, "b").unwrap().is_none());
+        assert!(storage.find_by_discord_id(50).unwrap().is_some());
+    }
+
+    #[test]
+    fn update_content_reindexes() {
+        let storage = storage();
+        storage.record(&outgoing("teh typo", 50, 1)).unwrap();
+        storage.update_content(50, "the typo").unwrap();
+
+        assert_eq!(
+            storage.find_by_discord_id(50).unwrap().unwrap().content,
+            "the typo"
+        );
+
+        let matches = |text: &str| {
+            storage
+                .search(&SearchQuery {
+                    text: Some(String::from(text)),
+                    limit: 10,
+                    ..Default::default()
+                })
+                .unwrap()
+                .1
+        };
+        assert_eq!(matches("the"), 1);
+        assert_eq!(matches("teh"), 0);
+    }
+
+    #[test]
+    fn search_by_text_nick_and_time() {
+        let storage = storage();
+        storage
+            .record(&incoming("#rust", "the borrow checker", "a", 10))
+            .unwrap();
+        storage
+            .record(&incoming("#Rust", "borrow it back", "b", 20))
+            .unwrap();
+        storage
+            .record(&incoming("#go", "goroutines", "c", 30))
+            .unwrap();
+        storage
+            .record(&outgoing("borrowing again", 50, 40))
+            .unwrap();
+
+        let search = |query: SearchQuery| {
+            let (results, total) = storage.search(&SearchQuery { limit: 10, ..query }).unwrap();
+            let contents: Vec<String> = results.into_iter().map(|r| r.content).collect();
+            (contents, total)
+        };
+
+        // Whole words, newest first
+        assert_eq!(
+            search(SearchQuery {
+                text: Some(String::from("borrow")),
+                ..Default::default()
+            }),
+            (
+                vec![
+                    String::from("borrow it back"),
+                    String::from("the borrow checker")
+                ],
+                2
+            )
+        );
+        // FTS operators are taken literally
+        assert_eq!(
+            search(SearchQuery {
+                text: Some(String::from("borrow OR goroutines")),
+                ..Default::default()
+            })
+            .1,
+            0
+        );
+        assert_eq!(
+            search(SearchQuery {
+                nick: Some(String::from("ALICE")),
+                channel: Some(String::from("#rust")),
+                ..Default::default()
+            })
+            .1,
+            2
+        );
+        assert_eq!(
+            search(SearchQuery {
+                since: Some(at(20)),
+                until: Some(at(40)),
+                ..Default::default()
+            }),
+            (
+                vec![String::from("goroutines"), String::from("borrow it back")],
+                2
+            )
+        );
+    }
+
+    #[test]
+    fn search_pages() {
+        let storage = storage();
+        for i in 1..=5 {
+            storage
+                .record(&outgoing(&format!("message {}", i), i, i))
+                .unwrap();
+        }
+
+        let page = |offset: usize| {
+            let (results, total) = storage
+                .search(&SearchQuery {
+                    offset,
+                    limit: 2,
+                    ..Default::default()
+                })
+                .unwrap();
+            let contents: Vec<String> = results.into_iter().map(|r| r.content).collect();
+            (contents, total)
+        };
+
+        assert_eq!(
+            page(0),
+            (
+                vec![String::from("message 5"), String::from("message 4")],
+                5
+            )
+        );
+        assert_eq!(page(4), (vec![String::from("message 1")], 5));
+        assert_eq!(page(usize::MAX), (vec![], 5));
+    }
+}
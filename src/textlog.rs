@@ -0,0 +1,515 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+// Line formats of the loggers people already have tooling for
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextLogFormat {
+    Znc,
+    Irssi,
+    Weechat,
+}
+
+pub enum LogEvent<'a> {
+    Message {
+        nick: &'a str,
+        content: &'a str,
+    },
+    Action {
+        nick: &'a str,
+        content: &'a str,
+    },
+    Notice {
+        nick: &'a str,
+        content: &'a str,
+    },
+    Join {
+        nick: &'a str,
+        host: &'a str,
+    },
+    Part {
+        nick: &'a str,
+        host: &'a str,
+        reason: &'a str,
+    },
+    Quit {
+        nick: &'a str,
+        host: &'a str,
+        reason: &'a str,
+    },
+    Kick {
+        nick: &'a str,
+        victim: &'a str,
+        reason: &'a str,
+    },
+    Nick {
+        old: &'a str,
+        new: &'a str,
+    },
+    Topic {
+        nick: &'a str,
+        topic: &'a str,
+    },
+}
+
+// Channel and nick names end up as directory names, so keep them from escaping the log directory.
+// IRC names are case-insensitive, so they are lowercased like ZNC does.
+pub fn file_name(name: &str) -> String {
+    let name = name.to_lowercase().replace(['/', '\\', '\0'], "_");
+
+    match name.starts_with('.') {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+// One file per day: <logs>/<network>/<channel>/YYYY-MM-DD.log
+fn day_path(logs_dir: &Path, network: &str, target: &str, date: NaiveDate) -> PathBuf {
+    logs_dir
+        .join(file_name(network))
+        .join(file_name(target))
+        .join(format!("{}.log", date.format("%Y-%m-%d")))
+}
+
+pub struct TextLog {
+    logs_dir: PathBuf,
+    network: String,
+    format: TextLogFormat,
+    // Open files by target, so a line is a single write instead of an open on the connection's task
+    files: HashMap<String, (NaiveDate, File)>,
+}
+
+impl TextLog {
+    pub fn new(logs_dir: &Path, network: &str, format: TextLogFormat) -> TextLog {
+        TextLog {
+            logs_dir: logs_dir.to_path_buf(),
+            network: String::from(network),
+            format,
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn write(&mut self, target: &str, time: DateTime<Utc>, event: &LogEvent) -> io::Result<()> {
+        let date = time.date_naive();
+        let key = file_name(target);
+
+        if !matches!(self.files.get(&key), Some((opened, _)) if *opened == date) {
+            // A new day, files from the previous ones won't be written to anymore
+            self.files.retain(|_, (opened, _)| *opened == date);
+
+            let path = day_path(&self.logs_dir, &self.network, target, date);
+            fs::create_dir_all(path.parent().unwrap())?;
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.files.insert(key.clone(), (date, file));
+        }
+
+        let line = format!("{}\n", self.format_line(target, time, event));
+        let (_, file) = self.files.get_mut(&key).unwrap();
+        file.write_all(line.as_bytes())
+    }
+
+    fn format_line(&self, channel: &str, time: DateTime<Utc>, event: &LogEvent) -> String {
+        match self.format {
+            TextLogFormat::Znc => {
+                let body = match *event {
+                    LogEvent::Message { nick, content } => format!("<{}> {}", nick, content),
+                    LogEvent::Action { nick, content } => format!("* {} {}", nick, content),
+                    LogEvent::Notice { nick, content } => format!("-{}- {}", nick, content),
+                    LogEvent::Join { nick, host } => format!("*** Joins: {} ({})", nick, host),
+                    LogEvent::Part { nick, host, reason } => {
+                        format!("*** Parts: {} ({}) ({})", nick, host, reason)
+                    }
+                    LogEvent::Quit { nick, host, reason } => {
+                        format!("*** Quits: {} ({}) ({})", nick, host, reason)
+                    }
+                    LogEvent::Kick {
+                        nick,
+                        victim,
+                        reason,
+                    } => format!("*** {} was kicked by {} ({})", victim, nick, reason),
+                    LogEvent::Nick { old, new } => format!("*** {} is now known as {}", old, new),
+                    LogEvent::Topic { nick, topic } => {
+                        format!("*** {} changes topic to '{}'", nick, topic)
+                    }
+                };
+                format!("[{}] {}", time.format("%H:%M:%S"), body)
+            }
+            TextLogFormat::Irssi => {
+                let body = match *event {
+                    LogEvent::Message { nick, content } => format!("< {}> {}", nick, content),
+                    LogEvent::Action { nick, content } => format!(" * {} {}", nick, content),
+                    LogEvent::Notice { nick, content } => {
+                        format!("-{}:{}- {}", nick, channel, content)
+                    }
+                    LogEvent::Join { nick, host } => {
+                        format!("-!- {} [{}] has joined {}", nick, host, channel)
+                    }
+                    LogEvent::Part { nick, host, reason } => {
+                        format!("-!- {} [{}] has left {} [{}]", nick, host, channel, reason)
+                    }
+                    LogEvent::Quit { nick, host, reason } => {
+                        format!("-!- {} [{}] has quit [{}]", nick, host, reason)
+                    }
+                    LogEvent::Kick {
+                        nick,
+                        victim,
+                        reason,
+                    } => format!(
+                        "-!- {} was kicked from {} by {} [{}]",
+                        victim, channel, nick, reason
+                    ),
+                    LogEvent::Nick { old, new } => format!("-!- {} is now known as {}", old, new),
+                    LogEvent::Topic { nick, topic } => {
+                        format!(
+                            "-!- {} changed the topic of {} to: {}",
+                            nick, channel, topic
+                        )
+                    }
+                };
+                format!("{} {}", time.format("%H:%M"), body)
+            }
+            // WeeChat logs are tab separated: time, prefix, message
+            TextLogFormat::Weechat => {
+                let body = match *event {
+                    LogEvent::Message { nick, content } => format!("{}\t{}", nick, content),
+                    LogEvent::Action { nick, content } => format!(" *\t{} {}", nick, content),
+                    LogEvent::Notice { nick, content } => {
+                        format!("--\tNotice({}): {}", nick, content)
+                    }
+                    LogEvent::Join { nick, host } => {
+                        format!("-->\t{} ({}) has joined {}", nick, host, channel)
+                    }
+                    LogEvent::Part { nick, host, reason } => {
+                        format!("<--\t{} ({}) has left {} ({})", nick, host, channel, reason)
+                    }
+                    LogEvent::Quit { nick, host, reason } => {
+                        format!("<--\t{} ({}) has quit ({})", nick, host, reason)
+                    }
+                    LogEvent::Kick {
+                        nick,
+                        victim,
+                        reason,
+                    } => format!("<--\t{} has kicked {} ({})", nick, victim, reason),
+                    LogEvent::Nick { old, new } => format!("--\t{} is now known as {}", old, new),
+                    LogEvent::Topic { nick, topic } => format!(
+                        "--\t{} has changed topic for {} to \"{}\"",
+                        nick, channel, topic
+                    ),
+                };
+                format!("{}\t{}", time.format("%Y-%m-%d %H:%M:%S"), body)
+            }
+        }
+    }
+}
+
+pub enum Export {
+    Empty,
+    TooLarge,
+    Archive(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct TooLarge;
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "archive too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+fn too_large(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<TooLarge>())
+}
+
+// An in-memory archive that refuses to grow past a limit, so a long date range fails early
+// instead of being zipped whole before its size is checked
+struct LimitedCursor {
+    cursor: Cursor<Vec<u8>>,
+    limit: usize,
+}
+
+impl Write for LimitedCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cursor.position() as usize + buf.len() > self.limit {
+            return Err(io::Error::other(TooLarge));
+        }
+        self.cursor.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for LimitedCursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.cursor.seek(pos)
+    }
+}
+
+// Zips the daily logs of a channel between two dates (inclusive), as long as the archive fits in limit bytes
+pub fn export(
+    logs_dir: &Path,
+    network: &str,
+    target: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    limit: usize,
+) -> io::Result<Export> {
+    let mut archive = ZipWriter::new(LimitedCursor {
+        cursor: Cursor::new(Vec::new()),
+        limit,
+    });
+    let mut empty = true;
+
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        let path = day_path(logs_dir, network, target, date);
+
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let written = match archive.start_file(
+            path.file_name().unwrap().to_string_lossy(),
+            FileOptions::default(),
+        ) {
+            Ok(()) => io::copy(&mut file, &mut archive),
+            Err(ZipError::Io(e)) => Err(e),
+            Err(e) => return Err(e.into()),
+        };
+
+        match written {
+            Ok(_) => empty = false,
+            Err(e) if too_large(&e) => return Ok(Export::TooLarge),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if empty {
+        return Ok(Export::Empty);
+    }
+
+    match archive.finish() {
+        Ok(archive) => Ok(Export::Archive(archive.cursor.into_inner())),
+        Err(ZipError::Io(e)) if too_large(&e) => Ok(Export::TooLarge),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::Read;
+
+    fn time() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, 9, 5, 7).unwrap()
+    }
+
+    fn line(format: TextLogFormat, event: &LogEvent) -> String {
+        TextLog::new(Path::new("logs"), "irc.example.org:6697", format).format_line(
+            "#rust",
+            time(),
+            event,
+        )
+    }
+
+    // A fresh directory for each test, removed again when it ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("textlog-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn znc_lines() {
+        let format = TextLogFormat::Znc;
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Message {
+                    nick: "alice",
+                    content: "hi"
+                }
+            ),
+            "[09:05:07] <alice> hi"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Action {
+                    nick: "alice",
+                    content: "waves"
+                }
+            ),
+            "[09:05:07] * alice waves"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Join {
+                    nick: "alice",
+                    host: "a@example.org"
+                }
+            ),
+            "[09:05:07] *** Joins: alice (a@example.org)"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Kick {
+                    nick: "op",
+                    victim: "bob",
+                    reason: "spam"
+                }
+            ),
+            "[09:05:07] *** bob was kicked by op (spam)"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Topic {
+                    nick: "op",
+                    topic: "Rust"
+                }
+            ),
+            "[09:05:07] *** op changes topic to 'Rust'"
+        );
+    }
+
+    #[test]
+    fn weechat_lines() {
+        let format = TextLogFormat::Weechat;
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Message {
+                    nick: "alice",
+                    content: "hi"
+                }
+            ),
+            "2024-03-01 09:05:07\talice\thi"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Notice {
+                    nick: "alice",
+                    content: "psst"
+                }
+            ),
+            "2024-03-01 09:05:07\t--\tNotice(alice): psst"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Part {
+                    nick: "alice",
+                    host: "a@example.org",
+                    reason: "bye"
+                }
+            ),
+            "2024-03-01 09:05:07\t<--\talice (a@example.org) has left #rust (bye)"
+        );
+        assert_eq!(
+            line(
+                format,
+                &LogEvent::Nick {
+                    old: "alice",
+                    new: "alice_"
+                }
+            ),
+            "2024-03-01 09:05:07\t--\talice is now known as alice_"
+        );
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("#Rust"), "#rust");
+        assert_eq!(file_name("#a/b\\c"), "#a_b_c");
+        assert_eq!(file_name(".."), "_..");
+        assert_eq!(
+            day_path(
+                Path::new("logs"),
+                "irc.example.org:6697",
+                "#Rust",
+                time().date_naive()
+            ),
+            Path::new("logs/irc.example.org:6697/#rust/2024-03-01.log")
+        );
+    }
+
+    #[test]
+    fn writes_a_file_per_day_and_exports_them() {
+        let dir = TempDir::new("export");
+        let network = "irc.example.org:6697";
+        let mut log = TextLog::new(&dir.0, network, TextLogFormat::Znc);
+        let message = LogEvent::Message {
+            nick: "alice",
+            content: "hi",
+        };
+
+        log.write("#Rust", time(), &message).unwrap();
+        log.write("#rust", time(), &message).unwrap();
+        log.write("#rust", time() + chrono::Duration::days(1), &message)
+            .unwrap();
+
+        let first = day_path(&dir.0, network, "#rust", time().date_naive());
+        assert_eq!(
+            fs::read_to_string(first).unwrap(),
+            "[09:05:07] <alice> hi\n[09:05:07] <alice> hi\n"
+        );
+
+        let (from, to) = (
+            time().date_naive(),
+            time().date_naive() + chrono::Duration::days(5),
+        );
+        let archive = match export(&dir.0, network, "#RUST", from, to, 1024 * 1024).unwrap() {
+            Export::Archive(archive) => archive,
+            _ => panic!("expected an archive"),
+        };
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["2024-03-01.log", "2024-03-02.log"]);
+
+        let mut contents = String::new();
+        archive
+            .by_name("2024-03-02.log")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "[09:05:07] <alice> hi\n");
+
+        assert!(matches!(
+            export(&dir.0, network, "#go", from, to, 1024 * 1024).unwrap(),
+            Export::Empty
+        ));
+        assert!(matches!(
+            export(&dir.0, network, "#rust", from, to, 64).unwrap(),
+            Export::TooLarge
+        ));
+    }
+}